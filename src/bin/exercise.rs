@@ -6,9 +6,39 @@ fn main() {
 
     let steps = if args.burn_in { u64::MAX } else { args.steps };
 
+    if let Some(path) = args.shrink.clone() {
+        let events = exercise::read_events(&path).expect("couldn't read events to shrink");
+        let (shrunk, violation) = exercise::shrink(&args, events);
+
+        let mut shrunk_path = path.into_os_string();
+        shrunk_path.push(".shrunk");
+        exercise::write_events(&shrunk_path, &shrunk).expect("couldn't write shrunk events");
+
+        eprintln!("{}", violation);
+        eprintln!(
+            "minimal failing schedule of {} events written to {:?}",
+            shrunk.len(),
+            shrunk_path
+        );
+        std::process::exit(1);
+    }
+
+    let replay = args
+        .replay
+        .as_ref()
+        .map(|path| exercise::read_events(path).expect("couldn't read events to replay"));
+
     let mut cluster = exercise::Cluster::start(args);
 
-    for _ in 0..steps {
-        cluster.step();
+    let ret = if let Some(events) = replay {
+        cluster.replay(events)
+    } else {
+        (0..steps).try_for_each(|_| cluster.step())
+    }
+    .and_then(|_| cluster.finish());
+
+    if let Err(violation) = ret {
+        eprintln!("{}", violation);
+        std::process::exit(1);
     }
 }
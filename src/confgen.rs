@@ -1,58 +1,454 @@
-use quickcheck::{Arbitrary, Gen, QuickCheck, RngCore};
-use rand::{rngs::StdRng, Rng};
+use std::fmt::Write;
+use std::io;
 
-#[derive(Debug, Clone)]
-struct SuperclusterConf {
-    clusters: Vec<ClusterConf>,
+#[cfg(test)]
+use std::collections::HashSet;
+#[cfg(test)]
+use std::io::{Read, Write as _};
+#[cfg(test)]
+use std::net::TcpStream;
+#[cfg(test)]
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+use quickcheck::{Arbitrary, Gen, QuickCheck};
+
+use crate::ports;
+
+// how long booted servers get to form all of their
+// routes and gateways.
+#[cfg(test)]
+const CONVERGENCE_TIMEOUT: Duration = Duration::from_secs(20);
+
+// client, route, gateway, monitoring and leaf node
+const PORTS_PER_SERVER: usize = 5;
+
+/// The JetStream domain of the hub's clusters, once
+/// there are leaf nodes with domains of their own.
+pub(crate) const HUB_DOMAIN: &str = "hub";
+
+/// The JetStream domain of the `i`th leaf cluster.
+pub(crate) fn leaf_domain(i: usize) -> String {
+    format!("leaf{}", i)
 }
 
-impl Arbitrary for SuperclusterConf {
-    fn arbitrary<G: Gen>(g: &mut G) -> SuperclusterConf {
-        let n_clusters: usize = g.gen_range(0..10);
-        SuperclusterConf {
-            clusters: g.gen::<Vec<ClusterConf>>(),
+/// One or more clusters, each a full mesh of routes,
+/// joined to each other by gateways if there is more
+/// than one, with optional leaf clusters that connect
+/// to the servers of all of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SuperclusterConf {
+    pub(crate) clusters: Vec<ClusterConf>,
+    pub(crate) leaves: Vec<ClusterConf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ClusterConf {
+    pub(crate) name: String,
+    // set for every cluster once there are leaf clusters,
+    // so that each one keeps its JetStream assets apart
+    pub(crate) domain: Option<String>,
+    pub(crate) leaf: bool,
+    pub(crate) servers: Vec<ServerConf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ServerConf {
+    pub(crate) name: String,
+    pub(crate) port: u16,
+    pub(crate) route_port: u16,
+    pub(crate) gateway_port: u16,
+    pub(crate) http_port: u16,
+    pub(crate) leafnode_port: u16,
+}
+
+impl SuperclusterConf {
+    /// Lays out clusters and leaf clusters of the given
+    /// sizes, numbering servers across all of them, with
+    /// the servers of leaf clusters coming last. Every
+    /// server listens on ports of this run's own block.
+    pub(crate) fn new(sizes: &[usize], leaf_sizes: &[usize]) -> io::Result<SuperclusterConf> {
+        let domains = !leaf_sizes.is_empty();
+
+        let total: usize = sizes.iter().chain(leaf_sizes).sum();
+        let mut ports = (0..total * PORTS_PER_SERVER)
+            .map(|_| ports::take())
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter();
+
+        let mut idx = 0;
+        let mut servers = |size: usize| -> Vec<ServerConf> {
+            let servers = (idx..idx + size)
+                .map(|idx| ServerConf::new(idx, &mut ports))
+                .collect();
+            idx += size;
+            servers
+        };
+
+        let clusters = sizes
+            .iter()
+            .enumerate()
+            .map(|(c, size)| ClusterConf {
+                name: format!("C{}", c),
+                domain: Some(HUB_DOMAIN.to_string()).filter(|_| domains),
+                leaf: false,
+                servers: servers(*size),
+            })
+            .collect();
+
+        let leaves = leaf_sizes
+            .iter()
+            .enumerate()
+            .map(|(l, size)| ClusterConf {
+                name: format!("L{}", l),
+                domain: Some(leaf_domain(l)),
+                leaf: true,
+                servers: servers(*size),
+            })
+            .collect();
+
+        Ok(SuperclusterConf { clusters, leaves })
+    }
+
+    pub(crate) fn servers(&self) -> impl Iterator<Item = (&ClusterConf, &ServerConf)> {
+        self.clusters
+            .iter()
+            .chain(&self.leaves)
+            .flat_map(|cluster| cluster.servers.iter().map(move |server| (cluster, server)))
+    }
+
+    pub(crate) fn server(&self, idx: usize) -> &ServerConf {
+        self.servers().nth(idx).expect("no such server").1
+    }
+
+    /// Renders the nats-server config file of server `idx`.
+    pub(crate) fn render(&self, idx: usize) -> String {
+        let (cluster, server) = self.servers().nth(idx).expect("no such server");
+
+        let mut out = String::new();
+
+        writeln!(out, "server_name: \"{}\"", server.name).unwrap();
+        writeln!(out, "listen: 127.0.0.1:{}", server.port).unwrap();
+        writeln!(out, "http: 127.0.0.1:{}", server.http_port).unwrap();
+
+        // JetStream itself is enabled on the command line
+        if let Some(domain) = &cluster.domain {
+            writeln!(out).unwrap();
+            writeln!(out, "jetstream {{").unwrap();
+            writeln!(out, "  domain: \"{}\"", domain).unwrap();
+            writeln!(out, "}}").unwrap();
+        }
+
+        if cluster.leaf {
+            self.render_leaf_remotes(&mut out);
+        } else if !self.leaves.is_empty() {
+            writeln!(out).unwrap();
+            writeln!(out, "leafnodes {{").unwrap();
+            writeln!(out, "  listen: 127.0.0.1:{}", server.leafnode_port).unwrap();
+            render_authorization(&mut out, "lu", "lp");
+            writeln!(out, "}}").unwrap();
+        }
+
+        // a standalone leaf server needs no cluster
+        if cluster.leaf && cluster.servers.len() == 1 {
+            return out;
+        }
+
+        writeln!(out).unwrap();
+        writeln!(out, "cluster {{").unwrap();
+        writeln!(out, "  name: \"{}\"", cluster.name).unwrap();
+        writeln!(out, "  no_advertise: true").unwrap();
+        writeln!(out, "  listen: 127.0.0.1:{}", server.route_port).unwrap();
+        render_authorization(&mut out, "cu", "cp");
+        writeln!(out, "  routes = [").unwrap();
+        for peer in &cluster.servers {
+            writeln!(out, "    nats-route://cu:cp@127.0.0.1:{}", peer.route_port).unwrap();
+        }
+        writeln!(out, "  ]").unwrap();
+        writeln!(out, "}}").unwrap();
+
+        // a lone cluster doesn't need a gateway, and
+        // servers without one are easier to reason about.
+        if self.clusters.len() > 1 && !cluster.leaf {
+            writeln!(out).unwrap();
+            writeln!(out, "gateway {{").unwrap();
+            writeln!(out, "  name: \"{}\"", cluster.name).unwrap();
+            writeln!(out, "  listen: 127.0.0.1:{}", server.gateway_port).unwrap();
+            render_authorization(&mut out, "gu", "gp");
+            writeln!(out, "  gateways = [").unwrap();
+            for remote in &self.clusters {
+                let urls: Vec<String> = remote
+                    .servers
+                    .iter()
+                    .map(|peer| format!("\"nats://gu:gp@127.0.0.1:{}\"", peer.gateway_port))
+                    .collect();
+                writeln!(
+                    out,
+                    "    {{ name: \"{}\", urls: [{}] }}",
+                    remote.name,
+                    urls.join(", ")
+                )
+                .unwrap();
+            }
+            writeln!(out, "  ]").unwrap();
+            writeln!(out, "}}").unwrap();
+        }
+
+        out
+    }
+
+    // every leaf server may connect to any server of the hub
+    fn render_leaf_remotes(&self, out: &mut String) {
+        let urls: Vec<String> = self
+            .clusters
+            .iter()
+            .flat_map(|cluster| &cluster.servers)
+            .map(|hub| format!("\"nats-leaf://lu:lp@127.0.0.1:{}\"", hub.leafnode_port))
+            .collect();
+
+        writeln!(out).unwrap();
+        writeln!(out, "leafnodes {{").unwrap();
+        writeln!(out, "  remotes = [").unwrap();
+        writeln!(out, "    {{ urls: [{}] }}", urls.join(", ")).unwrap();
+        writeln!(out, "  ]").unwrap();
+        writeln!(out, "}}").unwrap();
+    }
+}
+
+impl ServerConf {
+    fn new(idx: usize, ports: &mut impl Iterator<Item = u16>) -> ServerConf {
+        let mut port = || ports.next().expect("not enough free ports");
+        ServerConf {
+            name: format!("S{}", idx),
+            port: port(),
+            route_port: port(),
+            gateway_port: port(),
+            http_port: port(),
+            leafnode_port: port(),
         }
     }
+}
+
+fn render_authorization(out: &mut String, user: &str, password: &str) {
+    writeln!(out, "  authorization {{").unwrap();
+    writeln!(out, "    user: {}", user).unwrap();
+    writeln!(out, "    password: {}", password).unwrap();
+    writeln!(out, "    timeout: 0.5").unwrap();
+    writeln!(out, "  }}").unwrap();
+}
+
+#[cfg(test)]
+impl Arbitrary for SuperclusterConf {
+    fn arbitrary(g: &mut Gen) -> SuperclusterConf {
+        let n_clusters = *g.choose(&[1, 2, 3]).unwrap();
+        let sizes: Vec<usize> = (0..n_clusters)
+            .map(|_| *g.choose(&[1, 2, 3, 4, 5]).unwrap())
+            .collect();
+
+        let n_leaves = *g.choose(&[0, 0, 1, 2]).unwrap();
+        let leaf_sizes: Vec<usize> = (0..n_leaves)
+            .map(|_| *g.choose(&[1, 1, 3]).unwrap())
+            .collect();
 
+        SuperclusterConf::new(&sizes, &leaf_sizes).expect("no free ports")
+    }
+
+    // shrinks to fewer clusters and smaller ones, laid
+    // out again so that servers stay numbered densely.
     fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        let sizes = |clusters: &[ClusterConf]| -> Vec<usize> {
+            clusters
+                .iter()
+                .map(|cluster| cluster.servers.len())
+                .collect()
+        };
+
         Box::new(
-            self.clusters
+            (sizes(&self.clusters), sizes(&self.leaves))
                 .shrink()
-                .map(|clusters| SuperclusterConf { clusters }),
+                .filter(|(sizes, leaf_sizes)| {
+                    !sizes.is_empty() && !sizes.contains(&0) && !leaf_sizes.contains(&0)
+                })
+                .map(|(sizes, leaf_sizes)| {
+                    SuperclusterConf::new(&sizes, &leaf_sizes).expect("no free ports")
+                }),
         )
     }
 }
 
-#[derive(Debug, Clone)]
-struct ClusterConf {
-    servers: Vec<ServerConf>,
-}
+// every server but a standalone leaf server solicits
+// routes to exactly the servers of its own cluster, hub
+// servers gateways to every cluster of the hub and leaf
+// servers leaf connections to every hub server, and no
+// two servers share a name or a port.
+#[cfg(test)]
+fn prop_consistent(sc: SuperclusterConf) -> bool {
+    let mut names = HashSet::new();
+    let mut ports = HashSet::new();
+
+    for (idx, (cluster, server)) in sc.servers().enumerate() {
+        if !names.insert(server.name.clone()) {
+            return false;
+        }
+        for port in [
+            server.port,
+            server.route_port,
+            server.gateway_port,
+            server.http_port,
+            server.leafnode_port,
+        ] {
+            if !ports.insert(port) {
+                return false;
+            }
+        }
+
+        let conf = sc.render(idx);
 
-impl Arbitrary for ClusterConf {
-    fn arbitrary<G: Gen>(g: &mut G) -> ClusterConf {
-        todo!()
+        for (peer_cluster, peer) in sc.servers() {
+            let route = format!("nats-route://cu:cp@127.0.0.1:{}\n", peer.route_port);
+            let standalone = cluster.leaf && cluster.servers.len() == 1;
+            if conf.contains(&route) != (peer_cluster.name == cluster.name && !standalone) {
+                return false;
+            }
+
+            let gateway = format!("nats://gu:gp@127.0.0.1:{}\"", peer.gateway_port);
+            let gateways = sc.clusters.len() > 1 && !cluster.leaf && !peer_cluster.leaf;
+            if conf.contains(&gateway) != gateways {
+                return false;
+            }
+
+            let leafnode = format!("nats-leaf://lu:lp@127.0.0.1:{}\"", peer.leafnode_port);
+            if conf.contains(&leafnode) != (cluster.leaf && !peer_cluster.leaf) {
+                return false;
+            }
+        }
     }
 
-    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
-        Box::new(self.servers.shrink().map(|servers| ClusterConf { servers }))
+    true
+}
+
+// boots every server of the supercluster and waits for
+// each one to route to all of its cluster's peers, for hub
+// servers to have gateways to and from every other cluster
+// and for leaf servers to be connected to the hub.
+#[cfg(test)]
+fn prop_connectivity(sc: SuperclusterConf) -> bool {
+    let path = std::env::var("NATS_SERVER").unwrap_or_else(|_| "nats-server".into());
+    let n = sc.servers().count();
+    let _servers: Vec<crate::Server> = (0..n)
+        .map(|idx| crate::server(&path, &sc, idx, None))
+        .collect();
+
+    let started = Instant::now();
+    loop {
+        let missing: Vec<String> = (0..n).flat_map(|idx| missing_links(&sc, idx)).collect();
+
+        if missing.is_empty() {
+            return true;
+        }
+
+        if started.elapsed() > CONVERGENCE_TIMEOUT {
+            println!(
+                "{:?} did not converge within {:?}: {:?}",
+                sc, CONVERGENCE_TIMEOUT, missing
+            );
+            return false;
+        }
+
+        std::thread::sleep(Duration::from_millis(250));
     }
 }
 
-#[derive(Debug, Clone)]
-struct ServerConf {}
+// what server `idx` is still missing according to its
+// monitoring endpoint, empty once it's fully connected.
+#[cfg(test)]
+fn missing_links(sc: &SuperclusterConf, idx: usize) -> Vec<String> {
+    let (cluster, server) = sc.servers().nth(idx).unwrap();
+    let mut missing = vec![];
+
+    // servers may open several routes to the same peer,
+    // so they are told apart by the peer's id.
+    match monitor(server.http_port, "routez") {
+        Ok(routez) => {
+            let peers: HashSet<&str> = routez["routes"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|route| route["remote_id"].as_str())
+                .collect();
+            if peers.len() != cluster.servers.len() - 1 {
+                missing.push(format!(
+                    "{} routes to {} of {} peers",
+                    server.name,
+                    peers.len(),
+                    cluster.servers.len() - 1
+                ));
+            }
+        }
+        Err(e) => missing.push(format!("{} /routez: {}", server.name, e)),
+    }
+
+    if cluster.leaf {
+        match monitor(server.http_port, "leafz") {
+            Ok(leafz) if leafz["leafnodes"].as_u64().unwrap_or(0) == 0 => {
+                missing.push(format!("{} has no leaf connection to the hub", server.name))
+            }
+            Ok(_) => {}
+            Err(e) => missing.push(format!("{} /leafz: {}", server.name, e)),
+        }
+        return missing;
+    }
 
-impl Arbitrary for ServerConf {
-    fn arbitrary<G: Gen>(g: &mut G) -> ServerConf {
-        todo!()
+    if sc.clusters.len() == 1 {
+        return missing;
     }
+
+    match monitor(server.http_port, "gatewayz") {
+        Ok(gatewayz) => {
+            for remote in sc.clusters.iter().filter(|c| c.name != cluster.name) {
+                for direction in ["outbound_gateways", "inbound_gateways"] {
+                    if gatewayz[direction].get(&remote.name).is_none() {
+                        missing.push(format!(
+                            "{} has no {} to {}",
+                            server.name, direction, remote.name
+                        ));
+                    }
+                }
+            }
+        }
+        Err(e) => missing.push(format!("{} /gatewayz: {}", server.name, e)),
+    }
+
+    missing
 }
 
-fn prop_connectivity(sc: SuperclusterConf) -> bool {
-    false
+// fetches a JSON endpoint of a server's monitoring port
+#[cfg(test)]
+fn monitor(port: u16, endpoint: &str) -> io::Result<serde_json::Value> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+    stream.write_all(format!("GET /{} HTTP/1.0\r\n\r\n", endpoint).as_bytes())?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let body = response
+        .split("\r\n\r\n")
+        .nth(1)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no response body"))?;
+    serde_json::from_str(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[test]
+fn qc() {
+    QuickCheck::new().quickcheck(prop_consistent as fn(SuperclusterConf) -> bool);
 }
 
+// boots real servers, so it needs a nats-server binary,
+// taken from $NATS_SERVER or the PATH.
 #[test]
-pub fn qc() {
+#[ignore]
+fn connectivity() {
     QuickCheck::new()
+        .tests(10)
         .quickcheck(prop_connectivity as fn(SuperclusterConf) -> bool);
 }
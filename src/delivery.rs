@@ -0,0 +1,188 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::Violation;
+
+// a single message as delivered to a durable consumer
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Delivery {
+    pub stream_seq: u64,
+    pub consumer_seq: u64,
+    // how many times the server claims to have
+    // delivered this message, including this time
+    pub delivered: u64,
+    pub value: u64,
+}
+
+// we record every delivery to a durable consumer in
+// order, and ensure that its consumer sequence never
+// goes backwards, that once drained it delivered every
+// stream sequence it may not skip, e.g. because it wasn't
+// deleted or matches its filter, and that it never
+// redelivers a message more often than permitted.
+#[derive(Debug, Default)]
+pub(crate) struct DurableModel {
+    last_consumer_seq: u64,
+    last_stream_seq: u64,
+    // stream seq -> highest delivery count seen
+    delivered: BTreeMap<u64, u64>,
+    // stream seqs that a first delivery passed over, which
+    // may still come back as redeliveries if they were lost
+    // on their way to us
+    skipped: BTreeSet<u64>,
+}
+
+impl DurableModel {
+    pub(crate) fn has_delivered(&self, stream_seq: u64) -> bool {
+        self.delivered.contains_key(&stream_seq)
+    }
+
+    pub(crate) fn has_skipped(&self) -> bool {
+        !self.skipped.is_empty()
+    }
+
+    pub(crate) fn observe(
+        &mut self,
+        name: &str,
+        delivery: &Delivery,
+        max_deliver: i64,
+        skippable: impl Fn(u64) -> bool,
+    ) -> Result<(), Violation> {
+        if delivery.consumer_seq <= self.last_consumer_seq {
+            return Err(
+                Violation::correctness("A durable consumer's sequence went backwards.")
+                    .detail("consumer", name)
+                    .detail("previous consumer sequence", self.last_consumer_seq)
+                    .detail("consumer sequence", delivery.consumer_seq)
+                    .detail("stream sequence", delivery.stream_seq),
+            );
+        }
+        self.last_consumer_seq = delivery.consumer_seq;
+
+        if max_deliver > 0 && delivery.delivered > max_deliver as u64 {
+            return Err(Violation::correctness(
+                "A message was delivered to a durable consumer more often than max_deliver permits.",
+            )
+            .detail("consumer", name)
+            .detail("stream sequence", delivery.stream_seq)
+            .detail("delivery count", delivery.delivered)
+            .detail("max deliver", max_deliver));
+        }
+
+        if let Some(previous) = self.delivered.get(&delivery.stream_seq) {
+            if delivery.delivered <= *previous {
+                return Err(Violation::correctness(
+                    "A durable consumer's delivery count for a message went backwards.",
+                )
+                .detail("consumer", name)
+                .detail("stream sequence", delivery.stream_seq)
+                .detail("previous delivery count", previous)
+                .detail("delivery count", delivery.delivered));
+            }
+        }
+        self.delivered
+            .insert(delivery.stream_seq, delivery.delivered);
+        self.skipped.remove(&delivery.stream_seq);
+
+        // redeliveries legitimately jump back in the stream,
+        // and so do first deliveries that passed over one
+        // lost in flight, which only comes back redelivered.
+        if delivery.delivered == 1 && delivery.stream_seq > self.last_stream_seq {
+            let delivered = &self.delivered;
+            self.skipped.extend(
+                (self.last_stream_seq + 1..delivery.stream_seq)
+                    .filter(|seq| !skippable(*seq) && !delivered.contains_key(seq)),
+            );
+
+            self.last_stream_seq = delivery.stream_seq;
+        }
+
+        Ok(())
+    }
+
+    // called once the consumer is drained, when everything
+    // it passed over must have been delivered after all.
+    pub(crate) fn check_drained(
+        &self,
+        name: &str,
+        skippable: impl Fn(u64) -> bool,
+    ) -> Result<(), Violation> {
+        let skipped: Vec<u64> = self
+            .skipped
+            .iter()
+            .filter(|seq| !skippable(**seq))
+            .copied()
+            .collect();
+
+        if skipped.is_empty() {
+            return Ok(());
+        }
+
+        Err(Violation::correctness(
+            "A durable consumer skipped stream sequences that it had to deliver.",
+        )
+        .detail("consumer", name)
+        .detail("last stream sequence", self.last_stream_seq)
+        .detail("skipped stream sequences", skipped))
+    }
+}
+
+#[cfg(test)]
+fn delivery(stream_seq: u64, consumer_seq: u64, delivered: u64) -> Delivery {
+    Delivery {
+        stream_seq,
+        consumer_seq,
+        delivered,
+        value: stream_seq * 10,
+    }
+}
+
+#[test]
+fn durable_gaps() {
+    let mut model = DurableModel::default();
+    let deleted = |seq: u64| seq == 2;
+
+    model.observe("c", &delivery(1, 1, 1), -1, deleted).unwrap();
+    // skipping a deleted message is fine
+    model.observe("c", &delivery(3, 2, 1), -1, deleted).unwrap();
+    // and so is redelivering an earlier one
+    model.observe("c", &delivery(1, 3, 2), -1, deleted).unwrap();
+
+    // a message lost in flight is passed over, until it
+    // comes back as a redelivery
+    model.observe("c", &delivery(5, 4, 1), -1, deleted).unwrap();
+    model.observe("c", &delivery(4, 5, 2), -1, deleted).unwrap();
+    model.check_drained("c", deleted).unwrap();
+
+    model.observe("c", &delivery(7, 6, 1), -1, deleted).unwrap();
+    let gap = model.check_drained("c", deleted).unwrap_err();
+    assert!(gap.summary.contains("skipped"), "{}", gap);
+}
+
+#[test]
+fn durable_sequences() {
+    let mut model = DurableModel::default();
+    let none = |_: u64| false;
+
+    model.observe("c", &delivery(1, 1, 1), -1, none).unwrap();
+    model.observe("c", &delivery(2, 2, 1), -1, none).unwrap();
+
+    let backwards = model
+        .observe("c", &delivery(3, 2, 1), -1, none)
+        .unwrap_err();
+    assert!(
+        backwards.summary.contains("went backwards"),
+        "{}",
+        backwards
+    );
+
+    let mut model = DurableModel::default();
+    model.observe("c", &delivery(1, 1, 1), 2, none).unwrap();
+    model.observe("c", &delivery(1, 2, 2), 2, none).unwrap();
+
+    let redelivered = model.observe("c", &delivery(1, 3, 3), 2, none).unwrap_err();
+    assert!(
+        redelivered.summary.contains("max_deliver"),
+        "{}",
+        redelivered
+    );
+}
@@ -0,0 +1,242 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::mem;
+
+use crate::Violation;
+
+// what a publisher learned about one of its writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Write<S = u64> {
+    // JetStream acknowledged it at this stream seq
+    Acked(S),
+    // JetStream told us it was not stored
+    Failed,
+    // we don't know whether it was stored,
+    // e.g. because the acknowledgement timed out
+    Indeterminate,
+}
+
+// we record every sid:uuid pair, and
+// ensure that consumers never observe
+// different uuid's for the same stream id.
+// we also record what publishers learned
+// about every uuid they wrote, and ensure
+// that acked uuid's are never lost, that
+// failed ones never show up, and that
+// indeterminate ones show up at most once. a sid is a
+// plain stream seq for workloads with a single stream, or
+// e.g. a (stream, seq) pair for those with several.
+#[derive(Debug)]
+pub(crate) struct DurabilityModel<S = u64> {
+    observed: HashMap<S, u64>,
+    // uuid -> sid, to catch uuid's stored twice
+    observed_values: HashMap<u64, S>,
+    acked: BTreeMap<S, u64>,
+    writes: HashMap<u64, Write<S>>,
+    unvalidated_acks: Vec<(S, u64)>,
+    // stream sequences that were removed on
+    // purpose, which consumers may skip over
+    pub(crate) deleted: BTreeSet<S>,
+}
+
+impl<S> Default for DurabilityModel<S> {
+    fn default() -> DurabilityModel<S> {
+        DurabilityModel {
+            observed: HashMap::new(),
+            observed_values: HashMap::new(),
+            acked: BTreeMap::new(),
+            writes: HashMap::new(),
+            unvalidated_acks: vec![],
+            deleted: BTreeSet::new(),
+        }
+    }
+}
+
+impl<S: Copy + Ord + Hash + Debug> DurabilityModel<S> {
+    pub(crate) fn write(&mut self, value: u64, write: Write<S>) {
+        if let Write::Acked(seq) = write {
+            self.unvalidated_acks.push((seq, value));
+        }
+        self.writes.insert(value, write);
+    }
+
+    pub(crate) fn acked(&self) -> &BTreeMap<S, u64> {
+        &self.acked
+    }
+
+    // checks the acknowledgements received since
+    // the last call against everything observed.
+    pub(crate) fn validate_acks(&mut self) -> Result<(), Violation> {
+        for (seq, value) in mem::take(&mut self.unvalidated_acks) {
+            if let Some(observed) = self.observed.get(&seq) {
+                if *observed != value {
+                    return Err(Violation::correctness(
+                        "A consumer received a different value than JetStream \
+                        acknowledged for the same stream sequence.",
+                    )
+                    .detail("stream sequence", seq)
+                    .detail("acknowledged value", value)
+                    .detail("observed value", observed));
+                }
+            }
+
+            if let Some(old_value) = self.acked.insert(seq, value) {
+                return Err(Violation::correctness(
+                    "JetStream acknowledged two different writes at the same stream sequence.",
+                )
+                .detail("stream sequence", seq)
+                .detail("first acknowledged value", old_value)
+                .detail("second acknowledged value", value));
+            }
+        }
+
+        Ok(())
+    }
+
+    // records a value that a consumer received at a stream seq
+    pub(crate) fn observe(&mut self, seq: S, value: u64) -> Result<(), Violation> {
+        self.check_stored(seq, value)?;
+
+        if let Some(old_value) = self.observed.insert(seq, value) {
+            if value != old_value {
+                return Err(Violation::correctness(
+                    "Consumers received different values for the same stream sequence.",
+                )
+                .detail("stream sequence", seq)
+                .detail("first observed value", old_value)
+                .detail("second observed value", value));
+            }
+        }
+
+        self.observed_values.insert(value, seq);
+
+        Ok(())
+    }
+
+    // checks everything that is currently stored in the
+    // stream, as read back from it in one go.
+    pub(crate) fn check_stream(&self, stream: &BTreeMap<S, u64>) -> Result<(), Violation> {
+        let mut stored_values = HashMap::new();
+
+        for (seq, value) in stream {
+            self.check_stored(*seq, *value)?;
+
+            if let Some(first_seq) = stored_values.insert(*value, *seq) {
+                return Err(Violation::correctness(
+                    "A single write is stored at two different stream sequences.",
+                )
+                .detail("first stream sequence", first_seq)
+                .detail("second stream sequence", seq)
+                .detail("value", value));
+            }
+        }
+
+        for (seq, value) in &self.acked {
+            let stored = stream.get(seq);
+            if stored != Some(value) && !self.deleted.contains(seq) {
+                return Err(Violation::correctness(
+                    "An acknowledged write is missing from the stream.",
+                )
+                .detail("stream sequence", seq)
+                .detail("acknowledged value", value)
+                .detail("stored value", stored));
+            }
+        }
+
+        Ok(())
+    }
+
+    // at least one durable consumer sees the whole stream,
+    // so once they are drained, every acknowledged write
+    // must have been observed by one of them.
+    pub(crate) fn check_delivered(&self) -> Result<(), Violation> {
+        for (seq, value) in &self.acked {
+            if !self.observed.contains_key(seq) && !self.deleted.contains(seq) {
+                return Err(Violation::correctness(
+                    "An acknowledged write was never delivered to any consumer.",
+                )
+                .detail("stream sequence", seq)
+                .detail("acknowledged value", value));
+            }
+        }
+
+        Ok(())
+    }
+
+    // checks a single sid:uuid pair found in the stream
+    // against what its publisher learned about it.
+    fn check_stored(&self, seq: S, value: u64) -> Result<(), Violation> {
+        match self.writes.get(&value) {
+            None => Err(Violation::correctness(
+                "The stream contains a value that was never published.",
+            )
+            .detail("stream sequence", seq)
+            .detail("value", value)),
+            Some(Write::Failed) => Err(Violation::correctness(
+                "The stream contains a write that JetStream reported as failed.",
+            )
+            .detail("stream sequence", seq)
+            .detail("value", value)),
+            Some(Write::Acked(acked_seq)) if *acked_seq != seq => Err(Violation::correctness(
+                "A value is stored at a different stream sequence than JetStream acknowledged.",
+            )
+            .detail("acknowledged stream sequence", acked_seq)
+            .detail("stream sequence", seq)
+            .detail("value", value)),
+            Some(_) => match self.observed_values.get(&value) {
+                Some(first_seq) if *first_seq != seq => Err(Violation::correctness(
+                    "A single write is stored at two different stream sequences.",
+                )
+                .detail("first stream sequence", first_seq)
+                .detail("second stream sequence", seq)
+                .detail("value", value)),
+                _ => Ok(()),
+            },
+        }
+    }
+}
+
+#[test]
+fn lost_acks() {
+    let mut model = DurabilityModel::default();
+    model.write(10, Write::Acked(1));
+    model.write(11, Write::Indeterminate);
+    model.write(12, Write::Acked(3));
+    model.validate_acks().unwrap();
+
+    // the indeterminate write may or may not be stored
+    let stream: BTreeMap<u64, u64> = vec![(1, 10), (2, 11), (3, 12)].into_iter().collect();
+    model.check_stream(&stream).unwrap();
+    let stream: BTreeMap<u64, u64> = vec![(1, 10), (3, 12)].into_iter().collect();
+    model.check_stream(&stream).unwrap();
+
+    let stream: BTreeMap<u64, u64> = vec![(1, 10)].into_iter().collect();
+    let lost = model.check_stream(&stream).unwrap_err();
+    assert!(lost.summary.contains("missing"), "{}", lost);
+
+    // unless it was removed on purpose
+    model.deleted.insert(3);
+    model.check_stream(&stream).unwrap();
+}
+
+#[test]
+fn duplicate_and_failed_writes() {
+    let mut model = DurabilityModel::default();
+    model.write(10, Write::Indeterminate);
+    model.write(11, Write::Failed);
+
+    let stream: BTreeMap<u64, u64> = vec![(1, 10), (2, 10)].into_iter().collect();
+    let duplicate = model.check_stream(&stream).unwrap_err();
+    assert!(duplicate.summary.contains("two different"), "{}", duplicate);
+
+    model.observe(1, 10).unwrap();
+    let duplicate = model.observe(2, 10).unwrap_err();
+    assert!(duplicate.summary.contains("two different"), "{}", duplicate);
+
+    let failed = model.observe(3, 11).unwrap_err();
+    assert!(failed.summary.contains("failed"), "{}", failed);
+
+    let unknown = model.observe(4, 12).unwrap_err();
+    assert!(unknown.summary.contains("never published"), "{}", unknown);
+}
@@ -0,0 +1,554 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use nats::jetstream::{AckPolicy, ConsumerConfig, StreamConfig, StreamState};
+use nats::Headers;
+
+// how long we wait for any single message while
+// reading a whole stream back.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// the err_code JetStream rejects a publish with when
+// its expected last sequence header doesn't hold.
+const WRONG_LAST_SEQUENCE: u64 = 10071;
+
+// the API of whichever domain the connected server is in
+const API: &str = "$JS.API";
+
+/// The subject prefix of a JetStream domain's API, which
+/// reaches the domain from any server connected to it,
+/// e.g. a leaf node's domain from a server of its hub.
+pub(crate) fn api(domain: &str) -> String {
+    format!("$JS.{}.API", domain)
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiError {
+    code: u64,
+    err_code: Option<u64>,
+    description: Option<String>,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "jetstream error {}: {}",
+            self.code,
+            self.description.as_deref().unwrap_or_default()
+        )
+    }
+}
+
+// the response of JetStream API requests whose
+// result we only care about if they failed
+#[derive(Debug, Deserialize)]
+struct ApiResponse {
+    error: Option<ApiError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PubAckResponse {
+    seq: Option<u64>,
+    error: Option<ApiError>,
+}
+
+#[derive(Debug)]
+pub(crate) enum PublishError {
+    // JetStream answered that it did not store the message
+    Rejected(String),
+    // JetStream did not store the message because the
+    // stream's last sequence was not the expected one
+    Conflict(String),
+    // we don't know whether the message was stored
+    Indeterminate(io::Error),
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PublishError::Rejected(reason) => write!(f, "rejected: {}", reason),
+            PublishError::Conflict(reason) => write!(f, "conflict: {}", reason),
+            PublishError::Indeterminate(e) => write!(f, "indeterminate: {}", e),
+        }
+    }
+}
+
+/// Publishes a message to a stream subject and waits for
+/// JetStream to acknowledge it, returning the stream
+/// sequence it was persisted at.
+pub(crate) fn publish(
+    nc: &nats::Connection,
+    subject: &str,
+    payload: &[u8],
+    timeout: Duration,
+) -> Result<u64, PublishError> {
+    // a timeout or a dropped connection tells us nothing
+    // about whether the server received the message.
+    let response = nc
+        .request_timeout(subject, payload, timeout)
+        .map_err(PublishError::Indeterminate)?;
+
+    acknowledgement(&response.data)
+}
+
+/// Like `publish`, but attaches headers such as
+/// `Nats-Expected-Last-Subject-Sequence` to the message.
+pub(crate) fn publish_with_headers(
+    nc: &nats::Connection,
+    subject: &str,
+    headers: &Headers,
+    payload: &[u8],
+    timeout: Duration,
+) -> Result<u64, PublishError> {
+    let inbox = nc.new_inbox();
+
+    // nothing was sent yet if we can't even subscribe
+    let sub = nc
+        .subscribe(&inbox)
+        .map_err(|e| PublishError::Rejected(e.to_string()))?;
+
+    nc.publish_with_reply_or_headers(subject, Some(&inbox), Some(headers), payload)
+        .map_err(PublishError::Indeterminate)?;
+
+    let response = sub
+        .next_timeout(timeout)
+        .map_err(PublishError::Indeterminate)?;
+
+    acknowledgement(&response.data)
+}
+
+/// Builds message headers from name and value pairs.
+pub(crate) fn headers(pairs: &[(&str, &str)]) -> Headers {
+    let mut headers = Headers::default();
+    for (name, value) in pairs {
+        headers
+            .inner
+            .entry(name.to_string())
+            .or_default()
+            .insert(value.to_string());
+    }
+    headers
+}
+
+fn acknowledgement(data: &[u8]) -> Result<u64, PublishError> {
+    // servers reply without a body when nobody
+    // subscribes to the subject, in which case
+    // no stream could have stored the message.
+    if data.is_empty() {
+        return Err(PublishError::Rejected("no responders".into()));
+    }
+
+    let ack: PubAckResponse =
+        serde_json::from_slice(data).map_err(|e| PublishError::Indeterminate(e.into()))?;
+
+    match (ack.seq, ack.error) {
+        (_, Some(error)) if error.err_code == Some(WRONG_LAST_SEQUENCE) => {
+            Err(PublishError::Conflict(error.to_string()))
+        }
+        (_, Some(error)) => Err(PublishError::Rejected(error.to_string())),
+        (Some(seq), None) => Ok(seq),
+        (None, None) => Err(PublishError::Indeterminate(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "publish acknowledgement without a stream sequence",
+        ))),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MsgGetResponse {
+    message: Option<RawStoredMessage>,
+    error: Option<ApiError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawStoredMessage {
+    subject: String,
+    seq: u64,
+    // both base64 encoded
+    #[serde(default)]
+    hdrs: String,
+    #[serde(default)]
+    data: String,
+}
+
+// a message as stored in a stream, read back
+// through the stream's message lookup API.
+#[derive(Debug)]
+pub(crate) struct StoredMessage {
+    pub subject: String,
+    pub seq: u64,
+    // the raw header block, including the NATS/1.0 status line
+    pub headers: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+/// Looks up the last message stored in a stream for a
+/// subject, or None if the stream has no such message.
+pub(crate) fn last_message(
+    nc: &nats::Connection,
+    stream: &str,
+    subject: &str,
+    timeout: Duration,
+) -> io::Result<Option<StoredMessage>> {
+    get_message(
+        nc,
+        API,
+        stream,
+        serde_json::json!({ "last_by_subj": subject }),
+        timeout,
+    )
+}
+
+fn get_message(
+    nc: &nats::Connection,
+    api: &str,
+    stream: &str,
+    request: serde_json::Value,
+    timeout: Duration,
+) -> io::Result<Option<StoredMessage>> {
+    let response = nc.request_timeout(
+        &format!("{}.STREAM.MSG.GET.{}", api, stream),
+        request.to_string(),
+        timeout,
+    )?;
+
+    let response: MsgGetResponse = serde_json::from_slice(&response.data)?;
+
+    let base64 = |encoded: &str| {
+        base64::decode(encoded).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    };
+
+    match (response.message, response.error) {
+        (_, Some(error)) if error.code == 404 => Ok(None),
+        (_, Some(error)) => Err(io::Error::other(error.to_string())),
+        (Some(message), None) => Ok(Some(StoredMessage {
+            subject: message.subject,
+            seq: message.seq,
+            headers: base64(&message.hdrs)?,
+            data: base64(&message.data)?,
+        })),
+        (None, None) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "stream message response without a message",
+        )),
+    }
+}
+
+/// Changes the configuration of an existing stream, such as
+/// its limits or its number of replicas.
+pub(crate) fn update_stream(
+    nc: &nats::Connection,
+    config: &StreamConfig,
+    timeout: Duration,
+) -> io::Result<()> {
+    let response = nc.request_timeout(
+        &format!("$JS.API.STREAM.UPDATE.{}", config.name),
+        serde_json::to_vec(config)?,
+        timeout,
+    )?;
+
+    api_result(&response.data)
+}
+
+/// Creates a stream from its config as JSON, for settings
+/// such as per-subject limits, mirrors and sources that
+/// `StreamConfig` lacks.
+pub(crate) fn create_stream(
+    nc: &nats::Connection,
+    config: &serde_json::Value,
+    timeout: Duration,
+) -> io::Result<()> {
+    create_stream_at(nc, API, config, timeout)
+}
+
+/// Like `create_stream`, for a stream in another JetStream domain.
+pub(crate) fn create_stream_in(
+    nc: &nats::Connection,
+    domain: &str,
+    config: &serde_json::Value,
+    timeout: Duration,
+) -> io::Result<()> {
+    create_stream_at(nc, &api(domain), config, timeout)
+}
+
+fn create_stream_at(
+    nc: &nats::Connection,
+    api: &str,
+    config: &serde_json::Value,
+    timeout: Duration,
+) -> io::Result<()> {
+    let name = config["name"]
+        .as_str()
+        .expect("stream config without a name");
+    let response = nc.request_timeout(
+        &format!("{}.STREAM.CREATE.{}", api, name),
+        config.to_string(),
+        timeout,
+    )?;
+
+    api_result(&response.data)
+}
+
+/// Changes the number of replicas of a stream, leaving the
+/// rest of its config as the server reports it, including
+/// settings that `StreamConfig` lacks.
+pub(crate) fn scale_stream(
+    nc: &nats::Connection,
+    stream: &str,
+    replicas: usize,
+    timeout: Duration,
+) -> io::Result<()> {
+    let response = nc.request_timeout(&format!("{}.STREAM.INFO.{}", API, stream), "", timeout)?;
+    let mut info: serde_json::Value = serde_json::from_slice(&response.data)?;
+    if info.get("error").is_some() {
+        return api_result(&response.data);
+    }
+
+    let config = info.get_mut("config").ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "stream info without a config")
+    })?;
+    config["num_replicas"] = replicas.into();
+
+    let response = nc.request_timeout(
+        &format!("{}.STREAM.UPDATE.{}", API, stream),
+        config.to_string(),
+        timeout,
+    )?;
+
+    api_result(&response.data)
+}
+
+/// Deletes a stream in another JetStream domain.
+pub(crate) fn delete_stream_in(
+    nc: &nats::Connection,
+    domain: &str,
+    stream: &str,
+    timeout: Duration,
+) -> io::Result<()> {
+    let response = nc.request_timeout(
+        &format!("{}.STREAM.DELETE.{}", api(domain), stream),
+        "",
+        timeout,
+    )?;
+
+    api_result(&response.data)
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamInfoResponse {
+    state: Option<StreamState>,
+    error: Option<ApiError>,
+}
+
+/// Looks up the state of a stream in another JetStream domain.
+pub(crate) fn stream_state_in(
+    nc: &nats::Connection,
+    domain: &str,
+    stream: &str,
+    timeout: Duration,
+) -> io::Result<StreamState> {
+    let response = nc.request_timeout(
+        &format!("{}.STREAM.INFO.{}", api(domain), stream),
+        "",
+        timeout,
+    )?;
+
+    let response: StreamInfoResponse = serde_json::from_slice(&response.data)?;
+
+    match (response.state, response.error) {
+        (_, Some(error)) => Err(io::Error::other(error.to_string())),
+        (Some(state), None) => Ok(state),
+        (None, None) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "stream info response without a state",
+        )),
+    }
+}
+
+fn api_result(data: &[u8]) -> io::Result<()> {
+    let response: ApiResponse = serde_json::from_slice(data)?;
+
+    match response.error {
+        Some(error) => Err(io::Error::other(error.to_string())),
+        None => Ok(()),
+    }
+}
+
+/// Fetches up to `batch` messages from a pull consumer,
+/// without waiting for more messages to arrive in the stream.
+/// Messages the server sends after we stopped waiting are
+/// lost, and delivered again once their ack wait expired.
+pub(crate) fn fetch(
+    nc: &nats::Connection,
+    stream: &str,
+    consumer: &str,
+    batch: usize,
+    timeout: Duration,
+) -> io::Result<Vec<nats::Message>> {
+    let inbox = nc.new_inbox();
+    let sub = nc.subscribe(&inbox)?;
+
+    let request = serde_json::json!({ "batch": batch, "no_wait": true }).to_string();
+    nc.publish_request(
+        &format!("$JS.API.CONSUMER.MSG.NEXT.{}.{}", stream, consumer),
+        &inbox,
+        request,
+    )?;
+
+    let mut messages = vec![];
+    while messages.len() < batch {
+        let msg = match sub.next_timeout(timeout) {
+            Ok(msg) => msg,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut && !messages.is_empty() => break,
+            Err(e) => return Err(e),
+        };
+
+        // the server ends a batch early with a status message,
+        // such as 404 when there are no more messages, which
+        // has no reply subject to acknowledge.
+        if msg.reply.is_none() {
+            break;
+        }
+
+        messages.push(msg);
+    }
+
+    Ok(messages)
+}
+
+/// Sends an acknowledgement such as `+ACK`, `-NAK` or `+TERM`
+/// for a message, and waits for the server to confirm it.
+pub(crate) fn ack(
+    nc: &nats::Connection,
+    msg: &nats::Message,
+    kind: &str,
+    timeout: Duration,
+) -> io::Result<()> {
+    let reply = msg.reply.as_ref().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "message has no reply subject to acknowledge",
+        )
+    })?;
+    nc.request_timeout(reply, kind, timeout)?;
+    Ok(())
+}
+
+/// Reads every message currently stored in a stream one
+/// sequence at a time, which unlike `drain` needs no
+/// consumer, so it also works for work queue streams.
+pub(crate) fn messages(
+    nc: &nats::Connection,
+    stream: &str,
+) -> io::Result<BTreeMap<u64, StoredMessage>> {
+    let state = nc.stream_info(stream)?.state;
+    read_messages(nc, API, stream, state)
+}
+
+/// Like `messages`, for a stream in another JetStream domain.
+pub(crate) fn messages_in(
+    nc: &nats::Connection,
+    domain: &str,
+    stream: &str,
+) -> io::Result<BTreeMap<u64, StoredMessage>> {
+    let state = stream_state_in(nc, domain, stream, DRAIN_TIMEOUT)?;
+    read_messages(nc, &api(domain), stream, state)
+}
+
+fn read_messages(
+    nc: &nats::Connection,
+    api: &str,
+    stream: &str,
+    state: StreamState,
+) -> io::Result<BTreeMap<u64, StoredMessage>> {
+    let mut contents = BTreeMap::new();
+
+    if state.messages == 0 {
+        return Ok(contents);
+    }
+
+    for seq in state.first_seq..=state.last_seq {
+        let request = serde_json::json!({ "seq": seq });
+        if let Some(message) = get_message(nc, api, stream, request, DRAIN_TIMEOUT)? {
+            contents.insert(seq, message);
+        }
+    }
+
+    Ok(contents)
+}
+
+/// Like `messages`, for a stream of little-endian u64
+/// values, returning a map from stream sequence to value.
+pub(crate) fn read_by_seq(nc: &nats::Connection, stream: &str) -> io::Result<BTreeMap<u64, u64>> {
+    decode_all(messages(nc, stream)?)
+}
+
+/// Like `read_by_seq`, for a stream in another JetStream domain.
+pub(crate) fn read_by_seq_in(
+    nc: &nats::Connection,
+    domain: &str,
+    stream: &str,
+) -> io::Result<BTreeMap<u64, u64>> {
+    decode_all(messages_in(nc, domain, stream)?)
+}
+
+fn decode_all(messages: BTreeMap<u64, StoredMessage>) -> io::Result<BTreeMap<u64, u64>> {
+    messages
+        .into_iter()
+        .map(|(seq, message)| Ok((seq, decode(&message.data)?)))
+        .collect()
+}
+
+/// Reads every message currently stored in a stream of
+/// little-endian u64 values through an ephemeral
+/// consumer, returning a map from stream sequence to value.
+pub(crate) fn drain(nc: &nats::Connection, stream: &str) -> io::Result<BTreeMap<u64, u64>> {
+    let state = nc.stream_info(stream)?.state;
+    let last_seq = state.last_seq;
+
+    let mut contents = BTreeMap::new();
+
+    // a purged stream keeps its last sequence
+    if state.messages == 0 {
+        return Ok(contents);
+    }
+
+    let mut consumer = nc.create_consumer(
+        stream,
+        ConsumerConfig {
+            deliver_subject: Some(nc.new_inbox()),
+            ack_policy: AckPolicy::None,
+            ..Default::default()
+        },
+    )?;
+    consumer.timeout = DRAIN_TIMEOUT;
+
+    loop {
+        let (seq, value) = consumer.process_timeout(|msg| {
+            let info = msg.jetstream_message_info().unwrap();
+            Ok((info.stream_seq, decode(&msg.data)?))
+        })?;
+
+        contents.insert(seq, value);
+
+        if seq >= last_seq {
+            return Ok(contents);
+        }
+    }
+}
+
+pub(crate) fn decode(data: &[u8]) -> io::Result<u64> {
+    let bytes = data.try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected an 8 byte value, got {} bytes", data.len()),
+        )
+    })?;
+    Ok(u64::from_le_bytes(bytes))
+}
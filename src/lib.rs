@@ -1,17 +1,51 @@
-use std::collections::{HashMap, HashSet};
-use std::convert::TryInto;
+use std::collections::BTreeSet;
 use std::io;
-use std::mem;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command};
 use std::sync::atomic::{AtomicU64, Ordering::SeqCst};
+use std::time::{Duration, Instant};
 
 use rand::seq::{IteratorRandom, SliceRandom};
 use rand::{rngs::StdRng, Rng, SeedableRng};
 
-use nats::jetstream::{ConsumerConfig, RetentionPolicy, StreamConfig};
-
-const STREAM: &str = "exercise_stream";
+use nats::jetstream::RetentionPolicy;
+
+mod confgen;
+mod delivery;
+mod durability;
+mod jetstream;
+pub mod linearizability;
+mod liveness;
+mod partition;
+mod ports;
+mod proxy;
+mod schedule;
+mod shrink;
+mod throttle;
+mod violation;
+mod workload;
+
+pub use schedule::{read_events, write_events, Action, Event, Outcome};
+pub use shrink::shrink;
+pub use violation::Violation;
+pub use workload::{Disposition, Op};
+
+use confgen::SuperclusterConf;
+use liveness::Liveness;
+use partition::Network;
+use proxy::{Direction, Flow, Proxy};
+use schedule::EventLog;
+use throttle::Throttle;
+use workload::{Check, Workload};
+
+// how long a publisher waits for JetStream to
+// acknowledge that its message was persisted.
+const PUBLISH_TIMEOUT: Duration = Duration::from_millis(500);
+
+// how long we wait for JetStream to accept a new
+// number of replicas for a stream.
+const SCALE_TIMEOUT: Duration = Duration::from_secs(5);
 
 // generates unique (for this test run) ID
 fn idgen() -> u64 {
@@ -20,68 +54,140 @@ fn idgen() -> u64 {
 }
 
 pub struct Cluster {
-    clients: Vec<Consumer>,
+    clients: Vec<Client>,
     servers: Vec<Server>,
-    paused: HashSet<usize>,
+    // ordered sets keep choices made from them
+    // with the seeded rng replayable
+    paused: BTreeSet<usize>,
+    network: Option<Network>,
+    faulty_clients: BTreeSet<usize>,
     args: Args,
     rng: StdRng,
-    unvalidated_consumers: HashSet<usize>,
-    durability_model: DurabilityModel,
+    workload: Box<dyn Workload>,
+    liveness: Liveness,
+    throttle: Throttle,
+    event_log: EventLog,
+    step: u64,
+    started: Instant,
+    // the replicas we last scaled the workload's streams to
+    replicas: usize,
+    // set once the streams were scaled, until the
+    // workload checked that no writes were lost, which
+    // waits for every fault to heal
+    scaled: bool,
 }
 
 impl Cluster {
+    /// Starts local servers and injects faults into them
+    /// while running the workload.
     pub fn start(args: Args) -> Cluster {
         println!("Starting cluster exerciser with seed {}", args.seed);
 
-        let rng = SeedableRng::seed_from_u64(args.seed);
-
-        let servers: Vec<Server> = (0..args.servers)
-            .map(|i| server(&args.path, i as u16))
+        let (per_cluster, clusters) = (args.servers as usize, args.clusters as usize);
+        let conf =
+            SuperclusterConf::new(&vec![per_cluster; clusters], &vec![1; args.leaves as usize])
+                .expect("unable to allocate server ports");
+
+        let network = if args.partitions {
+            Some(Network::start(&conf))
+        } else {
+            None
+        };
+
+        // leaf servers come last, so faults may target
+        // them, but clients only connect to the hub.
+        let servers: Vec<Server> = (0..conf.servers().count())
+            .map(|i| {
+                let routes = network.as_ref().and_then(|n| n.routes(i));
+                server(&args.path, &conf, i, routes)
+            })
             .collect();
 
         // let servers come up
         std::thread::sleep(std::time::Duration::from_millis(2000));
 
-        println!("creating testing stream {}", STREAM);
+        // clients take turns between clusters. workloads create
+        // their streams through client 0, which places them in
+        // the first cluster, so the clients of every other
+        // cluster reach them over gateways.
+        let clients: Vec<Client> = (0..args.clients as usize)
+            .map(|id| {
+                let cluster = id % clusters;
+                let s = &servers[cluster * per_cluster + (id / clusters) % per_cluster];
+                let proxy = if args.client_faults {
+                    let target: SocketAddr = ([127, 0, 0, 1], s.port).into();
+                    let proxy = Proxy::spawn(target).expect("unable to start client proxy");
+                    Some(proxy)
+                } else {
+                    None
+                };
 
-        {
-            let nc = servers[0].nc();
+                let nc = match &proxy {
+                    Some(proxy) => connect(proxy.port),
+                    None => s.nc(),
+                };
+                Client { id, nc, proxy }
+            })
+            .collect();
 
-            let _ = nc.delete_stream(STREAM);
+        Cluster::new(args, servers, network, clients)
+    }
 
-            nc.create_stream(StreamConfig {
-                num_replicas: args.num_replicas,
-                name: STREAM.to_string(),
-                retention: RetentionPolicy::Limits,
-                ..Default::default()
+    /// Runs the workload against servers that are managed,
+    /// and possibly faulted, by someone else. Clients are
+    /// spread over `--servers` servers, and `connect` is
+    /// called with the index of the server a client should
+    /// connect to.
+    pub fn connect<F>(args: Args, mut connect: F) -> Cluster
+    where
+        F: FnMut(usize) -> nats::Connection,
+    {
+        println!("Connecting cluster exerciser with seed {}", args.seed);
+
+        let clients = (0..args.clients as usize)
+            .map(|id| Client {
+                id,
+                nc: connect(id % args.servers as usize),
+                proxy: None,
             })
-            .expect("couldn't create exercise_stream");
-        }
+            .collect();
 
-        let clients: Vec<Consumer> = servers
-            .iter()
-            .cycle()
-            .enumerate()
-            .take(args.clients as usize)
-            .map(|(id, s)| {
-                let consumer_name = format!("consumer_{}", id);
-                println!("creating testing consumer {}", consumer_name);
-
-                let nc = s.nc();
-                let conf = ConsumerConfig {
-                    deliver_subject: Some(consumer_name.clone()),
-                    durable_name: consumer_name.into(),
-                    ..Default::default()
-                };
-                Consumer {
-                    inner: nc
-                        .create_consumer(STREAM, conf)
-                        .expect("couldn't create consumer"),
-                    observed: Default::default(),
-                    id,
+        Cluster::new(args, vec![], None, clients)
+    }
+
+    fn new(
+        args: Args,
+        servers: Vec<Server>,
+        network: Option<Network>,
+        clients: Vec<Client>,
+    ) -> Cluster {
+        let rng = SeedableRng::seed_from_u64(args.seed);
+
+        let mut workload = workload::by_name(&args.workload).expect(USAGE);
+        loop {
+            match workload.setup(&clients, &args) {
+                Ok(()) => break,
+                // servers we didn't start ourselves
+                // may take a while to come up.
+                Err(e) if servers.is_empty() => {
+                    println!("couldn't set up the workload yet: {:?}", e);
+                    std::thread::sleep(Duration::from_secs(1));
                 }
-            })
-            .collect();
+                Err(e) => panic!("couldn't set up the workload: {:?}", e),
+            }
+        }
+
+        let replicas = args.num_replicas;
+        let liveness = Liveness::new(clients.len(), args.recovery_deadline);
+        let throttle = Throttle::new(args.progress_window);
+
+        let event_log_path = match (&args.event_log, &args.replay) {
+            (Some(path), _) => path.clone(),
+            (None, Some(replayed)) => schedule::derived_log(replayed, ".replayed"),
+            (None, None) => format!("events_{}.jsonl", args.seed).into(),
+        };
+        println!("recording events to {:?}", event_log_path);
+        let event_log = EventLog::create(&event_log_path).expect("couldn't create event log");
 
         Cluster {
             servers,
@@ -89,147 +195,510 @@ impl Cluster {
             rng: rng,
             args,
             paused: Default::default(),
-            durability_model: Default::default(),
-            unvalidated_consumers: Default::default(),
+            network,
+            faulty_clients: Default::default(),
+            workload,
+            liveness,
+            throttle,
+            event_log,
+            step: 0,
+            started: Instant::now(),
+            replicas,
+            scaled: false,
         }
     }
 
-    pub fn step(&mut self) {
-        match self.rng.gen_range(0..1000) {
-            0..=5 => self.restart_server(),
-            6..=40 => self.pause_server(),
-            41..=90 => self.resume_server(),
-            91..=200 => self.publish(),
-            201..=1000 => self.consume(),
-            _ => unreachable!("impossible choice"),
+    pub fn step(&mut self) -> Result<(), Violation> {
+        self.step += 1;
+
+        if let Some(action) = self.choose() {
+            self.execute(action);
+        }
+
+        if self.throttle.tick() && self.injects_faults() {
+            println!("healing cluster after sustained lack of progress");
+            for action in self.heal_actions() {
+                self.execute(action);
+            }
         }
-        self.validate();
+
+        self.check_healed();
+        self.validate()?;
+
+        self.checkpoint()?;
+
+        Ok(())
     }
 
-    fn restart_server(&mut self) {
-        if self.args.no_kill {
-            return;
+    /// Re-executes the events recorded by a previous run,
+    /// waiting until each one's original offset from the
+    /// start of the workload before applying it.
+    pub fn replay(&mut self, events: Vec<Event>) -> Result<(), Violation> {
+        println!("replaying {} recorded events", events.len());
+
+        for event in events {
+            if let Some(wait) = event.at.checked_sub(self.started.elapsed()) {
+                std::thread::sleep(wait);
+            }
+
+            self.step = event.step;
+
+            let outcome = self.execute(event.action.clone());
+
+            if outcome != event.outcome {
+                println!(
+                    "replayed step {} diverged: {:?} was recorded as {:?} but is now {:?}",
+                    event.step, event.action, event.outcome, outcome
+                );
+            }
+
+            self.check_healed();
+            self.validate()?;
+
+            self.checkpoint()?;
         }
-        let idx = self.rng.gen_range(0..self.servers.len());
-        println!("restarting server {}", idx);
 
-        self.servers[idx].restart();
-        self.paused.remove(&idx);
+        Ok(())
     }
 
-    fn pause_server(&mut self) {
-        if self.paused.len() == self.servers.len() {
-            // all servers already paused
-            return;
+    /// Heals every fault, waits out the recovery deadline,
+    /// and then runs the workload's final checks, e.g. that
+    /// no acknowledged write was lost.
+    pub fn finish(&mut self) -> Result<(), Violation> {
+        self.step += 1;
+
+        println!("healing all faults before the final workload check");
+        for action in self.heal_actions() {
+            self.execute(action);
+        }
+
+        // give the cluster the same grace period it gets
+        // after any other heal before we expect it to serve.
+        std::thread::sleep(self.args.recovery_deadline);
+
+        self.workload
+            .check(&self.clients, Check::Final)
+            .map_err(|violation| self.report(violation))
+    }
+
+    // lets the workload check its model against the cluster
+    // after it recovered from a heal, or after scaling its
+    // streams once no fault keeps them from being read back,
+    // to catch lost writes early.
+    fn checkpoint(&mut self) -> Result<(), Violation> {
+        let recovered = self.liveness.take_checkpoint();
+        let check = if self.scaled && self.faults_healed() {
+            self.scaled = false;
+            Check::Scaled
+        } else if recovered {
+            Check::Recovered
+        } else {
+            return Ok(());
+        };
+
+        self.workload
+            .check(&self.clients, check)
+            .map_err(|violation| self.report(violation))
+    }
+
+    // resolves every random choice of the next step
+    // into an action, or None if it would be a no-op.
+    fn choose(&mut self) -> Option<Action> {
+        // replicas are changed through the JetStream API, so
+        // this works for servers someone else manages too. we
+        // only scale a healthy cluster, whose streams have to
+        // be readable again soon after.
+        if self.args.scale_replicas && self.rng.gen_ratio(1, 200) {
+            if !self.faults_healed() {
+                return None;
+            }
+            return self.choose_scale();
         }
-        let mut idx = self.rng.gen_range(0..self.servers.len());
 
-        while self.paused.contains(&idx) {
-            idx = self.rng.gen_range(0..self.servers.len());
+        if !self.injects_faults() {
+            return Some(self.choose_op());
         }
 
-        println!("pausing server {}", idx);
+        let servers = self.servers.len();
+
+        let action = match self.rng.gen_range(0..1000) {
+            0..=70 if !self.throttle.admit_fault(&mut self.rng) => self.choose_op(),
+            0..=5 if self.args.no_kill => return None,
+            0..=5 => Action::Restart {
+                server: self.rng.gen_range(0..servers),
+            },
+            6..=40 => {
+                if self.paused.len() == servers {
+                    // all servers already paused
+                    return None;
+                }
+                let mut server = self.rng.gen_range(0..servers);
+                while self.paused.contains(&server) {
+                    server = self.rng.gen_range(0..servers);
+                }
+                Action::Pause { server }
+            }
+            41..=55 => self.choose_partition()?,
+            56..=70 => self.choose_client_fault()?,
+            71..=120 => {
+                // returns None if there is nothing to resume
+                let server = *self.paused.iter().choose(&mut self.rng)?;
+                Action::Resume { server }
+            }
+            121..=140 => {
+                if !self.is_partitioned() {
+                    return None;
+                }
+                Action::HealPartition
+            }
+            141..=160 => {
+                let client = *self.faulty_clients.iter().choose(&mut self.rng)?;
+                Action::HealClient { client }
+            }
+            161..=1000 => self.choose_op(),
+            _ => unreachable!("impossible choice"),
+        };
 
-        let pid = self.servers[idx].child.as_ref().unwrap().id();
+        Some(action)
+    }
 
-        unsafe {
-            if libc::kill(pid as libc::pid_t, libc::SIGSTOP) != 0 {
-                panic!("{:?}", io::Error::last_os_error());
+    fn choose_op(&mut self) -> Action {
+        Action::Op {
+            client: self.rng.gen_range(0..self.clients.len()),
+            op: self.workload.step(&mut self.rng),
+        }
+    }
+
+    fn choose_scale(&mut self) -> Option<Action> {
+        let (servers, current) = (self.args.servers as usize, self.replicas);
+        let replicas = [1, 3, 5]
+            .iter()
+            .copied()
+            .filter(|r| *r <= servers && *r != current)
+            .choose(&mut self.rng)?;
+
+        Some(Action::ScaleReplicas { replicas })
+    }
+
+    fn choose_partition(&mut self) -> Option<Action> {
+        // only servers of the same cluster have proxied
+        // routes between them, so faults stay within one.
+        let network = self.network.as_ref()?;
+        let mut servers = network.clusters().choose(&mut self.rng)?.clone();
+        let n = servers.len();
+
+        let action = match self.rng.gen_range(0..3) {
+            0 => Action::Isolate {
+                server: *servers.choose(&mut self.rng).unwrap(),
+            },
+            1 => {
+                servers.shuffle(&mut self.rng);
+                let (left, right) = servers.split_at(self.rng.gen_range(1..n));
+                Action::Split {
+                    left: left.to_vec(),
+                    right: right.to_vec(),
+                }
             }
+            2 => {
+                servers.shuffle(&mut self.rng);
+                Action::Block {
+                    from: servers[0],
+                    to: servers[1],
+                }
+            }
+            _ => unreachable!("impossible choice"),
+        };
+
+        Some(action)
+    }
+
+    fn choose_client_fault(&mut self) -> Option<Action> {
+        if !self.args.client_faults {
+            return None;
         }
 
-        self.paused.insert(idx);
+        let client = self.rng.gen_range(0..self.clients.len());
+
+        let action = match self.rng.gen_range(0..4) {
+            0 => Action::ClientDelay {
+                client,
+                latency_ms: self.rng.gen_range(1..=500),
+            },
+            1 => Action::ClientTruncate {
+                client,
+                after_bytes: self.rng.gen_range(0..64),
+            },
+            2 => Action::ClientStall { client },
+            3 => Action::ClientBlackhole { client },
+            _ => unreachable!("impossible choice"),
+        };
+
+        Some(action)
     }
 
-    fn resume_server(&mut self) {
-        if self.paused.is_empty() {
-            // nothing ot resume
-            return;
+    // the actions that bring the cluster
+    // back to a fault-free state.
+    fn heal_actions(&self) -> Vec<Action> {
+        let mut actions: Vec<Action> = self
+            .paused
+            .iter()
+            .map(|server| Action::Resume { server: *server })
+            .collect();
+
+        if self.is_partitioned() {
+            actions.push(Action::HealPartition);
         }
 
-        let idx = *self.paused.iter().choose(&mut self.rng).unwrap();
+        actions.extend(
+            self.faulty_clients
+                .iter()
+                .map(|client| Action::HealClient { client: *client }),
+        );
 
-        println!("resuming server {}", idx);
+        actions
+    }
 
-        let pid = self.servers[idx].child.as_ref().unwrap().id();
+    fn execute(&mut self, action: Action) -> Outcome {
+        let outcome = self.apply(&action);
 
-        unsafe {
-            if libc::kill(pid as libc::pid_t, libc::SIGCONT) != 0 {
-                panic!("{:?}", io::Error::last_os_error());
+        if action.is_fault() {
+            self.liveness.fault();
+        }
+
+        let event = Event {
+            step: self.step,
+            at: self.started.elapsed(),
+            action,
+            outcome: outcome.clone(),
+        };
+
+        self.event_log
+            .record(&event)
+            .expect("unable to write to event log");
+
+        outcome
+    }
+
+    fn apply(&mut self, action: &Action) -> Outcome {
+        match *action {
+            Action::Restart { server } => self.restart_server(server),
+            Action::Pause { server } => self.pause_server(server),
+            Action::Resume { server } => self.resume_server(server),
+            Action::Isolate { server } => {
+                println!("isolating server {} from its routes", server);
+                let n = self.servers.len();
+                let network = self.network.as_mut().expect("partitions are disabled");
+                for other in (0..n).filter(|other| *other != server) {
+                    network.cut(server, other);
+                }
+            }
+            Action::Split {
+                ref left,
+                ref right,
+            } => {
+                println!("partitioning servers {:?} from {:?}", left, right);
+                let network = self.network.as_mut().expect("partitions are disabled");
+                for a in left {
+                    for b in right {
+                        network.cut(*a, *b);
+                    }
+                }
+            }
+            Action::Block { from, to } => {
+                println!("dropping route traffic from server {} to {}", from, to);
+                let network = self.network.as_mut().expect("partitions are disabled");
+                network.block(from, to);
+            }
+            Action::HealPartition => {
+                println!("healing route partitions");
+                let network = self.network.as_mut().expect("partitions are disabled");
+                network.heal();
+            }
+            Action::ClientDelay { client, latency_ms } => {
+                let latency = Duration::from_millis(latency_ms);
+                println!("delaying traffic of client {} by {:?}", client, latency);
+                let proxy = self.client_proxy(client);
+                proxy.set_flow(Direction::Upstream, Flow::Delay(latency));
+                proxy.set_flow(Direction::Downstream, Flow::Delay(latency));
+                self.faulty_clients.insert(client);
+            }
+            Action::ClientTruncate {
+                client,
+                after_bytes,
+            } => {
+                println!(
+                    "dropping connection of client {} after {} bytes",
+                    client, after_bytes
+                );
+                // the client reconnects by itself,
+                // so there is nothing left to heal.
+                self.client_proxy(client).truncate(after_bytes);
+            }
+            Action::ClientStall { client } => {
+                println!("stalling reads of client {}", client);
+                let proxy = self.client_proxy(client);
+                proxy.set_flow(Direction::Downstream, Flow::Stall);
+                self.faulty_clients.insert(client);
+            }
+            Action::ClientBlackhole { client } => {
+                println!("blackholing traffic of client {}", client);
+                let proxy = self.client_proxy(client);
+                proxy.set_flow(Direction::Upstream, Flow::Blackhole);
+                proxy.set_flow(Direction::Downstream, Flow::Blackhole);
+                self.faulty_clients.insert(client);
+            }
+            Action::HealClient { client } => {
+                println!("healing network of client {}", client);
+                self.client_proxy(client).heal();
+                self.faulty_clients.remove(&client);
+            }
+            Action::ScaleReplicas { replicas } => return self.scale(replicas),
+            Action::Op { client, ref op } => {
+                let outcome = self.workload.apply(&self.clients[client], op);
+                if outcome.is_success() {
+                    self.liveness.progress(client);
+                    self.throttle.progress();
+                }
+                return outcome;
             }
         }
 
-        self.paused.remove(&idx);
+        Outcome::Ok
+    }
+
+    // changes the replicas of every stream of the workload.
+    // JetStream moves the stream to a new peer set, which
+    // must not lose any acknowledged write.
+    fn scale(&mut self, replicas: usize) -> Outcome {
+        println!("scaling streams to {} replicas", replicas);
+
+        let nc = &self.clients[0].nc;
+        for stream in self.workload.streams() {
+            let scaled = jetstream::scale_stream(nc, &stream, replicas, SCALE_TIMEOUT);
+
+            if let Err(e) = scaled {
+                // the update may still go through, so some
+                // streams may have been scaled already.
+                self.scaled = true;
+                return Outcome::Failed {
+                    error: format!("couldn't scale {}: {}", stream, e),
+                };
+            }
+        }
+
+        self.replicas = replicas;
+        self.scaled = true;
+
+        Outcome::Ok
     }
 
-    fn publish(&mut self) {
-        let c = self.clients.choose(&mut self.rng).unwrap();
-        let data = idgen().to_le_bytes();
-        c.inner.nc.publish(STREAM, data).unwrap();
+    fn is_partitioned(&self) -> bool {
+        self.network
+            .as_ref()
+            .map(Network::is_partitioned)
+            .unwrap_or(false)
     }
 
-    fn consume(&mut self) {
-        let c = self.clients.choose_mut(&mut self.rng).unwrap();
-        let proc_ret: io::Result<(u64, u64)> = c.inner.process_timeout(|msg| {
-            let info = msg.jetstream_message_info().unwrap();
+    // we only manage faults for the servers we started
+    fn injects_faults(&self) -> bool {
+        !self.servers.is_empty()
+    }
 
-            let id = u64::from_le_bytes((&*msg.data).try_into().unwrap());
-            Ok((info.stream_seq, id))
-        });
+    // we can't tell when faults injected by someone else
+    // heal, so the liveness checker stays disarmed for them.
+    fn check_healed(&mut self) {
+        if !self.injects_faults() {
+            return;
+        }
 
-        if let Ok((seq, id)) = proc_ret {
-            c.observed.insert(seq, id);
-            self.unvalidated_consumers.insert(c.id);
+        if self.faults_healed() {
+            self.liveness.healed();
         }
     }
 
-    fn validate(&mut self) {
-        // assert all consumers have witnessed messages in the correct order
-        let unvalidated_consumers = mem::take(&mut self.unvalidated_consumers);
-
-        for id in unvalidated_consumers {
-            let c = &mut self.clients[id];
-
-            let observed = mem::take(&mut c.observed);
-
-            for (id, value) in observed {
-                if let Some(old_value) = self.durability_model.observed.insert(id, value) {
-                    if value != old_value {
-                        eprintln!(
-                            "
-                            Correctness violation detected after running for {:?}.
-                            Consumers received different values for the same \
-                            stream sequence.
-                                stream sequence: {}
-                                first observed value: {}
-                                second observed value: {}
-                                schedule replay seed: {}
-                            ",
-                            self.args.start_time.elapsed(),
-                            id,
-                            old_value,
-                            value,
-                            self.args.seed
-                        );
-                        std::process::exit(1);
-                    }
-                }
+    fn faults_healed(&self) -> bool {
+        self.paused.is_empty() && !self.is_partitioned() && self.faulty_clients.is_empty()
+    }
+
+    fn client_proxy(&self, client: usize) -> &Proxy {
+        self.clients[client]
+            .proxy
+            .as_ref()
+            .expect("client faults are disabled")
+    }
+
+    fn restart_server(&mut self, idx: usize) {
+        println!("restarting server {}", idx);
+
+        self.servers[idx].restart();
+        self.paused.remove(&idx);
+    }
+
+    fn pause_server(&mut self, idx: usize) {
+        println!("pausing server {}", idx);
+
+        self.signal(idx, libc::SIGSTOP);
+        self.paused.insert(idx);
+    }
+
+    fn resume_server(&mut self, idx: usize) {
+        println!("resuming server {}", idx);
+
+        self.signal(idx, libc::SIGCONT);
+        self.paused.remove(&idx);
+    }
+
+    fn signal(&self, idx: usize, signal: libc::c_int) {
+        let pid = self.servers[idx].child.as_ref().unwrap().id();
+
+        unsafe {
+            if libc::kill(pid as libc::pid_t, signal) != 0 {
+                panic!("{:?}", io::Error::last_os_error());
             }
         }
     }
+
+    fn validate(&mut self) -> Result<(), Violation> {
+        if let Err(violation) = self.workload.check(&self.clients, Check::Step) {
+            return Err(self.report(violation));
+        }
+
+        if let Some(stall) = self.liveness.check() {
+            let violation = Violation::liveness(
+                "Clients failed to make progress within the recovery \
+                deadline after all faults were healed.",
+            )
+            .detail("healed for", stall.healed_for)
+            .detail("recovery deadline", self.args.recovery_deadline)
+            .detail("successful operations per client", stall.progress);
+            return Err(self.report(violation));
+        }
+
+        Ok(())
+    }
+
+    // fills in where and how to reproduce a violation
+    fn report(&self, mut violation: Violation) -> Violation {
+        violation.elapsed = self.args.start_time.elapsed();
+        violation.step = self.step;
+        violation.seed = self.args.seed;
+        violation
+    }
 }
 
 struct Server {
     child: Option<Child>,
     port: u16,
     storage_dir: String,
+    conf_path: String,
+    log_path: String,
     path: PathBuf,
-    idx: u16,
+    routes: Option<String>,
 }
 
 impl Server {
     fn nc(&self) -> nats::Connection {
-        nats::connect(&format!("localhost:{}", self.port)).unwrap()
+        connect(self.port)
     }
 
     fn restart(&mut self) {
@@ -237,7 +706,32 @@ impl Server {
         child.kill().unwrap();
         child.wait().unwrap();
 
-        *self = server(&self.path, self.idx);
+        // the storage directory is kept, so the server has
+        // to recover its JetStream state like after a crash.
+        self.spawn();
+    }
+
+    fn spawn(&mut self) {
+        let mut command = Command::new(&self.path);
+
+        command
+            .arg("-js")
+            .args(&["-sd", &self.storage_dir])
+            .args(&["-c", &self.conf_path])
+            .args(["-l", &self.log_path])
+            .arg("-V")
+            .arg("-D");
+
+        if let Some(routes) = &self.routes {
+            // advertise an address nobody listens on, so that
+            // servers can't gossip their way around the route
+            // proxies by implicitly dialing each other directly.
+            command
+                .args(["--routes", routes])
+                .args(["--cluster_advertise", "127.0.0.1:1"]);
+        }
+
+        self.child = Some(command.spawn().expect("unable to spawn nats-server"));
     }
 }
 
@@ -248,48 +742,54 @@ impl Drop for Server {
             child.wait().unwrap();
         }
         let _ = std::fs::remove_dir_all(&self.storage_dir);
+        let _ = std::fs::remove_file(&self.conf_path);
+        let _ = std::fs::remove_file(&self.log_path);
     }
 }
 
-/// Starts a local NATS server that gets killed on drop.
-fn server<P: AsRef<Path>>(path: P, idx: u16) -> Server {
-    let port = idx + 44000;
-    let storage_dir = format!("jetstream_test_{}", idx);
+fn connect(port: u16) -> nats::Connection {
+    nats::connect(&format!("localhost:{}", port)).unwrap()
+}
+
+/// Starts a local NATS server that gets killed on drop,
+/// configured as server `idx` of `conf`. If `routes` is
+/// set, it overrides the routes from the server's config.
+fn server<P: AsRef<Path>>(
+    path: P,
+    conf: &SuperclusterConf,
+    idx: usize,
+    routes: Option<String>,
+) -> Server {
+    // named after this process, so that runs sharing
+    // a working directory don't trample on each other.
+    let prefix = format!("jetstream_test_{}_{}", std::process::id(), idx);
+
+    let storage_dir = prefix.clone();
     let _ = std::fs::remove_dir_all(&storage_dir);
 
-    let supercluster_conf = format!("confs/supercluster_{}.conf", idx);
-
-    let child = Command::new(path.as_ref())
-        .args(&["--port", &port.to_string()])
-        .arg("-js")
-        .args(&["-sd", &storage_dir])
-        .args(&["-c", &supercluster_conf])
-        .arg("-V")
-        .arg("-D")
-        .spawn()
-        .expect("unable to spawn nats-server");
-
-    Server {
-        child: Some(child),
-        port,
+    let conf_path = format!("{}.conf", prefix);
+    std::fs::write(&conf_path, conf.render(idx)).expect("unable to write server config");
+
+    let mut server = Server {
+        child: None,
+        port: conf.server(idx).port,
         storage_dir,
+        conf_path,
+        log_path: format!("{}.log", prefix),
         path: path.as_ref().into(),
-        idx,
-    }
+        routes,
+    };
+    server.spawn();
+    server
 }
 
-struct Consumer {
-    inner: nats::jetstream::Consumer,
-    observed: HashMap<u64, u64>,
-    id: usize,
-}
-
-// we record every sid:uuid pair, and
-// ensure that consumers never observe
-// different uuid's for the same stream id.
-#[derive(Default, Debug)]
-struct DurabilityModel {
-    observed: HashMap<u64, u64>,
+/// A connection that a workload performs its
+/// operations on, optionally through a proxy that
+/// injects faults into its traffic.
+pub struct Client {
+    pub(crate) id: usize,
+    pub(crate) nc: nats::Connection,
+    proxy: Option<Proxy>,
 }
 
 const USAGE: &str = "
@@ -299,23 +799,76 @@ Options:
     --path=<p>      Path to nats-server binary [default: nats-server].
     --seed=<#>      Seed for replaying faults [default: None].
     --clients=<#>   Number of concurrent clients [default: 3].
-    --servers=<#>   Number of cluster servers [default: 3].
+    --servers=<#>   Number of servers per cluster [default: 3].
+    --clusters=<#>  Number of clusters, joined by gateways [default: 1].
+    --leaves=<#>    Number of leaf node servers connected to the clusters,
+                    each with a JetStream domain of its own [default: 0].
     --steps=<#>     Number of steps to take [default: 10000].
+    --workload=<w>  Client workload to run against the cluster, one
+                    of: stream, kv, pull, dedup, chain, mirror
+                    [default: stream].
+    --streams=<#>   Number of test streams the stream workload spreads
+                    its messages over [default: 1].
+    --subjects=<#>  Number of subjects per stream of the stream workload,
+                    which some consumers filter on [default: 1].
+    --config-changes  Let the stream workload update the limits and
+                    replicas of its streams, purge them, and recreate
+                    consumers while faults are active [default: unset].
     --replicas=<#>  Number of replicas for the JetStream test stream [default: 1].
+    --scale-replicas  Change the replicas of the workload's streams between
+                    1, 3 and 5, as far as there are enough servers, and
+                    check that no acknowledged write is lost [default: unset].
+    --retention=<r> Retention policy of the test stream, one of: limits,
+                    interest (stream and pull workloads) or workqueue
+                    (pull workload, whose clients then share a single
+                    consumer) [default: limits].
     --no-kill       Do not restart servers, just pause/resume them [default: unset].
     --burn-in       Ignore steps and run tests until we crash [default: unset].
+    --recovery-deadline=<d>  Time after healing all faults within which
+                    every client must make progress, e.g. 30s or 500ms [default: 30s].
+    --progress-window=<#>  Number of steps over which progress is measured
+                    for throttling faults [default: 200].
+    --partitions    Route cluster traffic through proxies and inject
+                    network partitions between servers [default: unset].
+    --client-faults Route client traffic through proxies and inject
+                    latency, stalls, blackholes and dropped
+                    connections [default: unset].
+    --log=<file>    Where to record every executed fault and
+                    operation, which is next to the replayed log
+                    with a .replayed suffix when replaying
+                    [default: events_<seed>.jsonl].
+    --replay=<file> Re-execute the events recorded in a previous
+                    run's log instead of choosing new ones [default: None].
+    --shrink=<file> Search for the smallest subset of the events recorded
+                    in a failing run's log that still fails the same way,
+                    and write it to <file>.shrunk [default: None].
 ";
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Args {
     path: PathBuf,
     seed: u64,
     clients: u8,
     servers: u8,
+    clusters: u8,
+    leaves: u8,
     pub steps: u64,
+    workload: String,
+    streams: usize,
+    subjects: usize,
+    config_changes: bool,
     num_replicas: usize,
+    scale_replicas: bool,
+    retention: RetentionPolicy,
     no_kill: bool,
     pub burn_in: bool,
+    partitions: bool,
+    client_faults: bool,
+    event_log: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+    pub shrink: Option<PathBuf>,
+    recovery_deadline: Duration,
+    progress_window: u64,
     start_time: std::time::Instant,
 }
 
@@ -326,10 +879,25 @@ impl Default for Args {
             seed: rand::thread_rng().gen(),
             clients: 3,
             servers: 3,
+            clusters: 1,
+            leaves: 0,
             steps: 10000,
+            workload: "stream".into(),
+            streams: 1,
+            subjects: 1,
+            config_changes: false,
             num_replicas: 1,
+            scale_replicas: false,
+            retention: RetentionPolicy::Limits,
             no_kill: false,
             burn_in: false,
+            partitions: false,
+            client_faults: false,
+            event_log: None,
+            replay: None,
+            shrink: None,
+            recovery_deadline: Duration::from_secs(30),
+            progress_window: 200,
             start_time: std::time::Instant::now(),
         }
     }
@@ -344,6 +912,24 @@ where
     iter.next().expect(USAGE).parse().expect(USAGE)
 }
 
+// parses durations like 30s, 500ms or 2m, with
+// bare numbers interpreted as seconds.
+fn parse_duration<'a, I>(mut iter: I) -> Duration
+where
+    I: Iterator<Item = &'a str>,
+{
+    let raw = iter.next().expect(USAGE);
+    let split = raw.find(|c: char| !c.is_ascii_digit()).unwrap_or(raw.len());
+    let (digits, unit) = raw.split_at(split);
+    let n: u64 = digits.parse().expect(USAGE);
+    match unit {
+        "ms" => Duration::from_millis(n),
+        "s" | "" => Duration::from_secs(n),
+        "m" => Duration::from_secs(n * 60),
+        other => panic!("unknown duration unit: {}, {}", other, USAGE),
+    }
+}
+
 impl Args {
     pub fn parse() -> Args {
         let mut args = Args::default();
@@ -354,13 +940,84 @@ impl Args {
                 "seed" => args.seed = parse(&mut splits),
                 "clients" => args.clients = parse(&mut splits),
                 "servers" => args.servers = parse(&mut splits),
+                "clusters" => args.clusters = parse(&mut splits),
+                "leaves" => args.leaves = parse(&mut splits),
                 "steps" => args.steps = parse(&mut splits),
+                "workload" => {
+                    args.workload = parse(&mut splits);
+                    if !workload::WORKLOADS.contains(&args.workload.as_str()) {
+                        panic!("unknown workload: {}, {}", args.workload, USAGE);
+                    }
+                }
+                "streams" => args.streams = parse(&mut splits),
+                "subjects" => args.subjects = parse(&mut splits),
                 "replicas" => args.num_replicas = parse(&mut splits),
+                "retention" => {
+                    args.retention = match splits.next().expect(USAGE) {
+                        "limits" => RetentionPolicy::Limits,
+                        "interest" => RetentionPolicy::Interest,
+                        "workqueue" => RetentionPolicy::WorkQueue,
+                        other => panic!("unknown retention policy: {}, {}", other, USAGE),
+                    }
+                }
+                "config-changes" => args.config_changes = true,
+                "scale-replicas" => args.scale_replicas = true,
                 "no-kill" => args.no_kill = true,
                 "burn-in" => args.burn_in = true,
+                "partitions" => args.partitions = true,
+                "client-faults" => args.client_faults = true,
+                "log" => args.event_log = Some(parse(&mut splits)),
+                "replay" => args.replay = Some(parse(&mut splits)),
+                "shrink" => args.shrink = Some(parse(&mut splits)),
+                "recovery-deadline" => args.recovery_deadline = parse_duration(&mut splits),
+                "progress-window" => args.progress_window = parse(&mut splits),
                 other => panic!("unknown option: {}, {}", other, USAGE),
             }
         }
+
+        if args.servers == 0 || args.clusters == 0 {
+            panic!("at least one server and cluster are needed, {}", USAGE);
+        }
+
+        if args.partitions && args.servers < 2 {
+            panic!(
+                "partitions need at least two servers per cluster, {}",
+                USAGE
+            );
+        }
+
+        if args.workload == "mirror" && args.leaves == 0 {
+            panic!(
+                "the mirror workload needs at least one leaf node, {}",
+                USAGE
+            );
+        }
+
+        if args.event_log.is_some()
+            && (args.event_log == args.replay || args.event_log == args.shrink)
+        {
+            panic!(
+                "the event log would overwrite the events it reads, {}",
+                USAGE
+            );
+        }
+
+        if args.streams == 0 || args.subjects == 0 {
+            panic!("at least one stream and subject are needed, {}", USAGE);
+        }
+
+        let supported = match args.retention {
+            RetentionPolicy::Limits => true,
+            RetentionPolicy::Interest => ["stream", "pull"].contains(&args.workload.as_str()),
+            RetentionPolicy::WorkQueue => args.workload == "pull",
+        };
+        if !supported {
+            panic!(
+                "the {} workload doesn't support {:?} retention, {}",
+                args.workload, args.retention, USAGE
+            );
+        }
+
         args
     }
 }
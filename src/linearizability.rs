@@ -0,0 +1,412 @@
+//! Checks histories of concurrent operations for
+//! linearizability against a sequential model.
+
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::fmt::Debug;
+use std::hash::Hash;
+
+/// The sequential specification of a concurrent object,
+/// which a recorded history is checked against.
+pub trait Model: Clone + Eq + Hash {
+    type Call: Debug;
+    type Ret: Debug;
+
+    // the state after an operation, or None if the operation
+    // can't have returned `ret` in this state. `ret` is None
+    // if the caller never learned what the operation returned.
+    fn step(&self, call: &Self::Call, ret: Option<&Self::Ret>) -> Option<Self>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status<R> {
+    Pending,
+    Ok(R),
+    // we don't know whether the operation took effect, or
+    // what it returned, e.g. because its response timed out
+    Info,
+    // the operation definitely did not take effect
+    Fail,
+}
+
+#[derive(Debug, Clone)]
+pub struct Operation<C, R> {
+    pub invoked: u64,
+    pub completed: Option<u64>,
+    pub call: C,
+    pub status: Status<R>,
+}
+
+impl<C, R> Operation<C, R> {
+    // what the operation returned, if it completed
+    fn ret(&self) -> Option<&R> {
+        match &self.status {
+            Status::Ok(ret) => Some(ret),
+            _ => None,
+        }
+    }
+}
+
+/// The operations that clients invoked on a concurrent
+/// object, and when they completed, in real-time order.
+#[derive(Debug, Clone)]
+pub struct History<C, R> {
+    operations: Vec<Operation<C, R>>,
+    clock: u64,
+}
+
+impl<C, R> Default for History<C, R> {
+    fn default() -> History<C, R> {
+        History {
+            operations: vec![],
+            clock: 0,
+        }
+    }
+}
+
+impl<C, R> History<C, R> {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Records the invocation of an operation, returning
+    /// the id to record its completion with.
+    pub fn invoke(&mut self, call: C) -> usize {
+        let invoked = self.tick();
+        self.operations.push(Operation {
+            invoked,
+            completed: None,
+            call,
+            status: Status::Pending,
+        });
+        self.operations.len() - 1
+    }
+
+    pub fn complete(&mut self, id: usize, ret: R) {
+        let completed = self.tick();
+        let operation = &mut self.operations[id];
+        operation.completed = Some(completed);
+        operation.status = Status::Ok(ret);
+    }
+
+    // an indeterminate operation may take effect at any
+    // time after its invocation, or never.
+    pub fn info(&mut self, id: usize) {
+        self.operations[id].status = Status::Info;
+    }
+
+    pub fn fail(&mut self, id: usize) {
+        self.operations[id].status = Status::Fail;
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+}
+
+/// Searches for an order of the operations in a history that
+/// respects their real-time order and is valid for the model,
+/// in the style of Wing & Gong with Lowe's memoization of
+/// explored configurations. Pending operations are treated as
+/// indeterminate ones. On failure, returns the completed
+/// operation in front of which the longest valid order got
+/// stuck.
+pub fn check<M: Model>(
+    initial: M,
+    history: &History<M::Call, M::Ret>,
+) -> Result<(), &Operation<M::Call, M::Ret>> {
+    let entries: Vec<&Operation<M::Call, M::Ret>> = history
+        .operations
+        .iter()
+        .filter(|op| !matches!(op.status, Status::Fail))
+        .collect();
+
+    let mut linearized = vec![false; entries.len()];
+    let mut remaining = entries.iter().filter(|op| op.ret().is_some()).count();
+    let mut state = initial;
+    // the operations linearized so far, with the state before each
+    let mut stack: Vec<(usize, M)> = vec![];
+    let mut seen: HashSet<(Vec<bool>, M)> = HashSet::new();
+    let mut cursor = 0;
+    let mut deepest = (0, 0);
+
+    while remaining > 0 {
+        // an operation that completed before another one was
+        // invoked has to be linearized before that one.
+        let (horizon, blocking) = entries
+            .iter()
+            .enumerate()
+            .filter(|(i, op)| !linearized[*i] && op.ret().is_some())
+            .filter_map(|(i, op)| op.completed.map(|c| (c, i)))
+            .min()
+            .unwrap();
+
+        let mut next = None;
+        for i in cursor..entries.len() {
+            if entries[i].invoked > horizon {
+                break;
+            }
+            if linearized[i] {
+                continue;
+            }
+            if let Some(after) = state.step(&entries[i].call, entries[i].ret()) {
+                linearized[i] = true;
+                let unseen = seen.insert((linearized.clone(), after.clone()));
+                linearized[i] = false;
+                if unseen {
+                    next = Some((i, after));
+                    break;
+                }
+            }
+        }
+
+        if let Some((i, after)) = next {
+            linearized[i] = true;
+            if entries[i].ret().is_some() {
+                remaining -= 1;
+            }
+            stack.push((i, std::mem::replace(&mut state, after)));
+            cursor = 0;
+            continue;
+        }
+
+        if stack.len() >= deepest.0 {
+            deepest = (stack.len(), blocking);
+        }
+
+        let (i, before) = stack.pop().ok_or(entries[deepest.1])?;
+        linearized[i] = false;
+        if entries[i].ret().is_some() {
+            remaining += 1;
+        }
+        state = before;
+        cursor = i + 1;
+    }
+
+    Ok(())
+}
+
+/// A register holding a single value, or nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Register<T>(pub Option<T>);
+
+#[derive(Debug, Clone)]
+pub enum RegisterCall<T> {
+    Write(T),
+    Read,
+    CompareAndSet { expected: Option<T>, new: T },
+}
+
+#[derive(Debug, Clone)]
+pub enum RegisterRet<T> {
+    Ok,
+    Read(Option<T>),
+    Conflict,
+}
+
+impl<T: Clone + Eq + Hash + Debug> Model for Register<T> {
+    type Call = RegisterCall<T>;
+    type Ret = RegisterRet<T>;
+
+    fn step(&self, call: &RegisterCall<T>, ret: Option<&RegisterRet<T>>) -> Option<Self> {
+        match (call, ret) {
+            (RegisterCall::Write(value), None | Some(RegisterRet::Ok)) => {
+                Some(Register(Some(value.clone())))
+            }
+            (RegisterCall::Read, None) => Some(self.clone()),
+            (RegisterCall::Read, Some(RegisterRet::Read(value))) if *value == self.0 => {
+                Some(self.clone())
+            }
+            (RegisterCall::CompareAndSet { expected, new }, None | Some(RegisterRet::Ok))
+                if *expected == self.0 =>
+            {
+                Some(Register(Some(new.clone())))
+            }
+            (RegisterCall::CompareAndSet { expected, .. }, Some(RegisterRet::Conflict))
+                if *expected != self.0 =>
+            {
+                Some(self.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A set that values are only ever added to.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Set(pub BTreeSet<u64>);
+
+#[derive(Debug, Clone)]
+pub enum SetCall {
+    Add(u64),
+    Read,
+}
+
+#[derive(Debug, Clone)]
+pub enum SetRet {
+    Ok,
+    Read(BTreeSet<u64>),
+}
+
+impl Model for Set {
+    type Call = SetCall;
+    type Ret = SetRet;
+
+    fn step(&self, call: &SetCall, ret: Option<&SetRet>) -> Option<Set> {
+        match (call, ret) {
+            (SetCall::Add(value), None | Some(SetRet::Ok)) => {
+                let mut set = self.clone();
+                set.0.insert(*value);
+                Some(set)
+            }
+            (SetCall::Read, None) => Some(self.clone()),
+            (SetCall::Read, Some(SetRet::Read(values))) if *values == self.0 => Some(self.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// A FIFO queue.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Queue(pub VecDeque<u64>);
+
+#[derive(Debug, Clone)]
+pub enum QueueCall {
+    Enqueue(u64),
+    Dequeue,
+}
+
+#[derive(Debug, Clone)]
+pub enum QueueRet {
+    Ok,
+    // None if the queue was empty
+    Dequeued(Option<u64>),
+}
+
+impl Model for Queue {
+    type Call = QueueCall;
+    type Ret = QueueRet;
+
+    fn step(&self, call: &QueueCall, ret: Option<&QueueRet>) -> Option<Queue> {
+        let mut queue = self.clone();
+        match (call, ret) {
+            (QueueCall::Enqueue(value), None | Some(QueueRet::Ok)) => {
+                queue.0.push_back(*value);
+                Some(queue)
+            }
+            (QueueCall::Dequeue, None) => {
+                queue.0.pop_front();
+                Some(queue)
+            }
+            (QueueCall::Dequeue, Some(QueueRet::Dequeued(value))) => {
+                if queue.0.pop_front() == *value {
+                    Some(queue)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn sequential_register() {
+    let mut history = History::default();
+
+    let write = history.invoke(RegisterCall::Write(1));
+    history.complete(write, RegisterRet::Ok);
+    let read = history.invoke(RegisterCall::Read);
+    history.complete(read, RegisterRet::Read(Some(1)));
+
+    assert!(check(Register::default(), &history).is_ok());
+
+    // a stale read after the write completed
+    let read = history.invoke(RegisterCall::Read);
+    history.complete(read, RegisterRet::Read(None));
+
+    let stuck = check(Register::default(), &history).unwrap_err();
+    assert!(matches!(stuck.status, Status::Ok(RegisterRet::Read(None))));
+}
+
+#[test]
+fn concurrent_register() {
+    let mut history = History::default();
+
+    // two overlapping writes, and reads that
+    // observe them in either order.
+    let w1 = history.invoke(RegisterCall::Write(1));
+    let w2 = history.invoke(RegisterCall::Write(2));
+    let r1 = history.invoke(RegisterCall::Read);
+    history.complete(r1, RegisterRet::Read(Some(2)));
+    history.complete(w2, RegisterRet::Ok);
+    let r2 = history.invoke(RegisterCall::Read);
+    history.complete(r2, RegisterRet::Read(Some(1)));
+    history.complete(w1, RegisterRet::Ok);
+
+    assert!(check(Register::default(), &history).is_ok());
+
+    // once both writes completed, the value can't flip back.
+    let r3 = history.invoke(RegisterCall::Read);
+    history.complete(r3, RegisterRet::Read(Some(2)));
+
+    assert!(check(Register::default(), &history).is_err());
+}
+
+#[test]
+fn indeterminate_register_writes() {
+    let mut history = History::default();
+
+    let lost = history.invoke(RegisterCall::Write(1));
+    history.info(lost);
+    let failed = history.invoke(RegisterCall::Write(2));
+    history.fail(failed);
+
+    let read = history.invoke(RegisterCall::Read);
+    history.complete(read, RegisterRet::Read(None));
+    let cas = history.invoke(RegisterCall::CompareAndSet {
+        expected: None,
+        new: 3,
+    });
+    history.complete(cas, RegisterRet::Conflict);
+    let read = history.invoke(RegisterCall::Read);
+    history.complete(read, RegisterRet::Read(Some(1)));
+
+    // the lost write took effect after the first read
+    assert!(check(Register::default(), &history).is_ok());
+
+    // but the failed one never does
+    let read = history.invoke(RegisterCall::Read);
+    history.complete(read, RegisterRet::Read(Some(2)));
+
+    assert!(check(Register::default(), &history).is_err());
+}
+
+#[test]
+fn set_and_queue() {
+    let mut set = History::default();
+    let add = set.invoke(SetCall::Add(1));
+    set.complete(add, SetRet::Ok);
+    let lost = set.invoke(SetCall::Add(2));
+    set.info(lost);
+    let read = set.invoke(SetCall::Read);
+    set.complete(read, SetRet::Read(vec![1].into_iter().collect()));
+    assert!(check(Set::default(), &set).is_ok());
+
+    let read = set.invoke(SetCall::Read);
+    set.complete(read, SetRet::Read(BTreeSet::new()));
+    assert!(check(Set::default(), &set).is_err());
+
+    let mut queue = History::default();
+    for value in 1..=2 {
+        let enqueue = queue.invoke(QueueCall::Enqueue(value));
+        queue.complete(enqueue, QueueRet::Ok);
+    }
+    let dequeue = queue.invoke(QueueCall::Dequeue);
+    queue.complete(dequeue, QueueRet::Dequeued(Some(2)));
+    assert!(check(Queue::default(), &queue).is_err());
+}
@@ -0,0 +1,76 @@
+use std::time::{Duration, Instant};
+
+// after every heal, every client must manage to
+// successfully publish or consume something before
+// the recovery deadline expires.
+#[derive(Debug)]
+pub(crate) struct Liveness {
+    deadline: Duration,
+    healed_at: Option<Instant>,
+    progress: Vec<u64>,
+    checkpointed: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct Stall {
+    pub healed_for: Duration,
+    pub progress: Vec<u64>,
+}
+
+impl Liveness {
+    pub(crate) fn new(clients: usize, deadline: Duration) -> Liveness {
+        Liveness {
+            deadline,
+            healed_at: None,
+            progress: vec![0; clients],
+            checkpointed: false,
+        }
+    }
+
+    // called whenever a fault is injected, which
+    // disarms the deadline until the next heal.
+    pub(crate) fn fault(&mut self) {
+        self.healed_at = None;
+    }
+
+    // called after every step in which no fault is
+    // active. only the first call after a fault
+    // starts the recovery deadline.
+    pub(crate) fn healed(&mut self) {
+        if self.healed_at.is_none() {
+            self.healed_at = Some(Instant::now());
+            self.checkpointed = false;
+            for count in &mut self.progress {
+                *count = 0;
+            }
+        }
+    }
+
+    pub(crate) fn progress(&mut self, client: usize) {
+        self.progress[client] += 1;
+    }
+
+    // returns true exactly once per heal, as soon as
+    // every client made progress since that heal.
+    pub(crate) fn take_checkpoint(&mut self) -> bool {
+        if self.healed_at.is_none() || self.checkpointed || self.progress.contains(&0) {
+            return false;
+        }
+        self.checkpointed = true;
+        true
+    }
+
+    pub(crate) fn check(&self) -> Option<Stall> {
+        let healed_at = self.healed_at?;
+        let healed_for = healed_at.elapsed();
+
+        if healed_for > self.deadline && self.progress.contains(&0) {
+            Some(Stall {
+                healed_for,
+                progress: self.progress.clone(),
+            })
+        } else {
+            None
+        }
+    }
+}
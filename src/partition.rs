@@ -0,0 +1,125 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+
+use crate::confgen::SuperclusterConf;
+use crate::proxy::{Direction, Flow, Proxy};
+
+// every server dials each of its peers through a
+// dedicated proxy, so the route between any two
+// servers can be cut in either direction without
+// needing root or iptables. servers of different
+// clusters only talk over gateways, which aren't
+// proxied, so cutting them apart does nothing.
+#[derive(Debug)]
+pub(crate) struct Network {
+    proxies: HashMap<(usize, usize), Proxy>,
+    // the servers of every cluster with routes to cut
+    clusters: Vec<Vec<usize>>,
+    // (from, to) pairs whose traffic is blackholed
+    blocked: BTreeSet<(usize, usize)>,
+}
+
+impl Network {
+    pub(crate) fn start(conf: &SuperclusterConf) -> Network {
+        let mut proxies = HashMap::new();
+
+        let mut clusters: Vec<Vec<usize>> = vec![];
+        let mut names = vec![];
+        for (idx, (cluster, _)) in conf.servers().enumerate() {
+            match names.iter().position(|name| *name == cluster.name) {
+                Some(pos) => clusters[pos].push(idx),
+                None => {
+                    names.push(cluster.name.clone());
+                    clusters.push(vec![idx]);
+                }
+            }
+        }
+        clusters.retain(|servers| servers.len() > 1);
+
+        for (from, (cluster, _)) in conf.servers().enumerate() {
+            for (to, (peer_cluster, peer)) in conf.servers().enumerate() {
+                if to == from || peer_cluster.name != cluster.name {
+                    continue;
+                }
+                let target: SocketAddr = ([127, 0, 0, 1], peer.route_port).into();
+                let proxy = Proxy::spawn(target).expect("unable to start route proxy");
+                proxies.insert((from, to), proxy);
+            }
+        }
+
+        Network {
+            proxies,
+            clusters,
+            blocked: Default::default(),
+        }
+    }
+
+    // the servers of each cluster that has more than one,
+    // which are the only ones that can be partitioned.
+    pub(crate) fn clusters(&self) -> &[Vec<usize>] {
+        &self.clusters
+    }
+
+    // the routes that server `from` should solicit,
+    // formatted for nats-server's --routes flag, or
+    // None if it has no peers to route to.
+    pub(crate) fn routes(&self, from: usize) -> Option<String> {
+        let mut ports: Vec<u16> = self
+            .proxies
+            .iter()
+            .filter(|((dialer, _), _)| *dialer == from)
+            .map(|(_, proxy)| proxy.port)
+            .collect();
+        ports.sort_unstable();
+
+        if ports.is_empty() {
+            return None;
+        }
+
+        let routes = ports
+            .iter()
+            .map(|port| format!("nats-route://cu:cp@127.0.0.1:{}", port))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        Some(routes)
+    }
+
+    pub(crate) fn is_partitioned(&self) -> bool {
+        !self.blocked.is_empty()
+    }
+
+    // drops every byte that `from` sends to `to`, on
+    // both the route `from` dialed and the route
+    // `to` dialed. traffic from `to` to `from` is
+    // unaffected unless blocked separately.
+    pub(crate) fn block(&mut self, from: usize, to: usize) {
+        if !self.proxies.contains_key(&(from, to)) {
+            return;
+        }
+        self.proxies[&(from, to)].set_flow(Direction::Upstream, Flow::Blackhole);
+        self.proxies[&(to, from)].set_flow(Direction::Downstream, Flow::Blackhole);
+        self.blocked.insert((from, to));
+    }
+
+    // cuts both directions and tears down the
+    // existing route connections, so both sides
+    // notice immediately instead of waiting for
+    // their pings to time out.
+    pub(crate) fn cut(&mut self, a: usize, b: usize) {
+        if !self.proxies.contains_key(&(a, b)) {
+            return;
+        }
+        self.block(a, b);
+        self.block(b, a);
+        self.proxies[&(a, b)].sever();
+        self.proxies[&(b, a)].sever();
+    }
+
+    pub(crate) fn heal(&mut self) {
+        for (from, to) in std::mem::take(&mut self.blocked) {
+            self.proxies[&(from, to)].set_flow(Direction::Upstream, Flow::Open);
+            self.proxies[&(to, from)].set_flow(Direction::Downstream, Flow::Open);
+        }
+    }
+}
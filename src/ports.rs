@@ -0,0 +1,87 @@
+use std::io;
+use std::net::TcpListener;
+use std::sync::Mutex;
+
+// servers and proxies listen on ports below linux's default
+// ephemeral range (32768-60999), so that no outgoing
+// connection can take one while a server restarts.
+const FIRST_PORT: u16 = 10000;
+const LAST_PORT: u16 = 32767;
+
+// every run claims a block of ports for itself by listening
+// on its first one until it exits, so that runs going on in
+// parallel never hand out the same ports.
+const BLOCK_SIZE: u16 = 1000;
+
+static BLOCK: Mutex<Option<Block>> = Mutex::new(None);
+
+struct Block {
+    // held for as long as the process runs
+    _lock: TcpListener,
+    // the port right after the one we hold
+    first: u16,
+    next: u16,
+}
+
+impl Block {
+    fn claim() -> io::Result<Block> {
+        (FIRST_PORT..=LAST_PORT - BLOCK_SIZE)
+            .step_by(BLOCK_SIZE as usize)
+            .find_map(|first| {
+                let lock = TcpListener::bind(("127.0.0.1", first)).ok()?;
+                Some(Block {
+                    _lock: lock,
+                    first: first + 1,
+                    next: first + 1,
+                })
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "every block of test ports is claimed by another run",
+                )
+            })
+    }
+}
+
+/// Hands out a port of this run's block that nothing listens
+/// on. Ports are handed out in turn, wrapping around once the
+/// block is used up, which skips those still in use.
+pub(crate) fn take() -> io::Result<u16> {
+    let mut block = BLOCK.lock().unwrap();
+    if block.is_none() {
+        *block = Some(Block::claim()?);
+    }
+    let block = block.as_mut().unwrap();
+
+    for _ in 1..BLOCK_SIZE {
+        let port = block.next;
+        block.next += 1;
+        if block.next == block.first + BLOCK_SIZE - 1 {
+            block.next = block.first;
+        }
+
+        if TcpListener::bind(("127.0.0.1", port)).is_ok() {
+            return Ok(port);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        "every port of this run's block is in use",
+    ))
+}
+
+#[test]
+fn distinct_ports() {
+    let ports: Vec<u16> = (0..100).map(|_| take().unwrap()).collect();
+
+    let mut distinct = ports.clone();
+    distinct.sort_unstable();
+    distinct.dedup();
+    assert_eq!(distinct.len(), ports.len());
+
+    assert!(ports
+        .iter()
+        .all(|port| (FIRST_PORT..=LAST_PORT).contains(port)));
+}
@@ -0,0 +1,250 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::ports;
+
+// how often blocked reads and accepts wake up to
+// notice state changes and shutdown.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Direction {
+    // bytes flowing from the dialer to the target
+    Upstream,
+    // bytes flowing from the target back to the dialer
+    Downstream,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Flow {
+    Open,
+    // every chunk is held back before being forwarded
+    Delay(Duration),
+    // nothing is read, so bytes pile up in the
+    // kernel buffers until the sender blocks
+    Stall,
+    // bytes are read and silently discarded
+    Blackhole,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct State {
+    upstream: Flow,
+    downstream: Flow,
+    // bumped to sever every connection
+    // established before the bump
+    generation: u64,
+    // if set, the next chunk forwarded in either
+    // direction is cut off after this many bytes
+    // and its connection is severed.
+    truncate: Option<usize>,
+}
+
+impl State {
+    fn flow(&self, direction: Direction) -> Flow {
+        match direction {
+            Direction::Upstream => self.upstream,
+            Direction::Downstream => self.downstream,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    shutdown: AtomicBool,
+}
+
+/// A userspace TCP proxy that forwards every connection
+/// accepted on a free `port` to a target address, and that can
+/// blackhole traffic in either direction or sever its
+/// established connections. Stops accepting on drop.
+#[derive(Debug)]
+pub(crate) struct Proxy {
+    pub(crate) port: u16,
+    shared: Arc<Shared>,
+}
+
+impl Proxy {
+    pub(crate) fn spawn(target: SocketAddr) -> io::Result<Proxy> {
+        let port = ports::take()?;
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                upstream: Flow::Open,
+                downstream: Flow::Open,
+                generation: 0,
+                truncate: None,
+            }),
+            shutdown: AtomicBool::new(false),
+        });
+
+        let accept_shared = shared.clone();
+        thread::spawn(move || accept_loop(listener, target, accept_shared));
+
+        Ok(Proxy { port, shared })
+    }
+
+    pub(crate) fn set_flow(&self, direction: Direction, flow: Flow) {
+        let mut state = self.shared.state.lock().unwrap();
+        match direction {
+            Direction::Upstream => state.upstream = flow,
+            Direction::Downstream => state.downstream = flow,
+        }
+    }
+
+    // closes every connection currently going
+    // through this proxy. new connections are
+    // still accepted afterwards.
+    pub(crate) fn sever(&self) {
+        self.shared.state.lock().unwrap().generation += 1;
+    }
+
+    // severs the next connection to forward a chunk,
+    // after only `at` bytes of that chunk went through,
+    // which usually leaves a protocol frame half-written.
+    pub(crate) fn truncate(&self, at: usize) {
+        self.shared.state.lock().unwrap().truncate = Some(at);
+    }
+
+    pub(crate) fn heal(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.upstream = Flow::Open;
+        state.downstream = Flow::Open;
+        state.truncate = None;
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, SeqCst);
+    }
+}
+
+fn accept_loop(listener: TcpListener, target: SocketAddr, shared: Arc<Shared>) {
+    while !shared.shutdown.load(SeqCst) {
+        let dialer = match listener.accept() {
+            Ok((dialer, _)) => dialer,
+            Err(_) => {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+        };
+
+        let target = match TcpStream::connect(target) {
+            Ok(target) => target,
+            // the dialer will notice the closed
+            // connection and retry on its own.
+            Err(_) => continue,
+        };
+
+        let generation = shared.state.lock().unwrap().generation;
+
+        let streams = dialer
+            .set_nonblocking(false)
+            .and_then(|_| Ok((dialer.try_clone()?, target.try_clone()?)));
+
+        if let Ok((dialer_clone, target_clone)) = streams {
+            let up_shared = shared.clone();
+            thread::spawn(move || pump(dialer, target, Direction::Upstream, generation, up_shared));
+
+            let down_shared = shared.clone();
+            thread::spawn(move || {
+                pump(
+                    target_clone,
+                    dialer_clone,
+                    Direction::Downstream,
+                    generation,
+                    down_shared,
+                )
+            });
+        }
+    }
+}
+
+fn pump(
+    mut from: TcpStream,
+    mut to: TcpStream,
+    direction: Direction,
+    generation: u64,
+    shared: Arc<Shared>,
+) {
+    let _ = from.set_read_timeout(Some(POLL_INTERVAL));
+    let mut buf = vec![0; 64 * 1024];
+
+    while !shared.shutdown.load(SeqCst) {
+        let state = *shared.state.lock().unwrap();
+
+        if state.generation != generation {
+            break;
+        }
+
+        if state.flow(direction) == Flow::Stall {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        let read = from.read(&mut buf);
+
+        let (flow, truncate) = {
+            let mut state = shared.state.lock().unwrap();
+
+            if state.generation != generation {
+                break;
+            }
+
+            let flow = state.flow(direction);
+            let truncate = match (&read, flow) {
+                (Ok(n), Flow::Open) | (Ok(n), Flow::Delay(_)) if *n > 0 => state.truncate.take(),
+                _ => None,
+            };
+
+            if truncate.is_some() {
+                state.generation += 1;
+            }
+
+            (flow, truncate)
+        };
+
+        let n = match read {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if is_timeout(&e) => continue,
+            Err(_) => break,
+        };
+
+        if let Some(at) = truncate {
+            let _ = to.write_all(&buf[..at.min(n)]);
+            break;
+        }
+
+        let forwarded = match flow {
+            Flow::Open | Flow::Stall => to.write_all(&buf[..n]),
+            Flow::Delay(latency) => {
+                thread::sleep(latency);
+                to.write_all(&buf[..n])
+            }
+            Flow::Blackhole => Ok(()),
+        };
+
+        if forwarded.is_err() {
+            break;
+        }
+    }
+
+    let _ = from.shutdown(Shutdown::Both);
+    let _ = to.shutdown(Shutdown::Both);
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
@@ -0,0 +1,257 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Op;
+
+/// A single fault or client operation, with every random
+/// choice already resolved so that it can be re-executed
+/// verbatim from an event log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    Restart {
+        server: usize,
+    },
+    Pause {
+        server: usize,
+    },
+    Resume {
+        server: usize,
+    },
+    Isolate {
+        server: usize,
+    },
+    Split {
+        left: Vec<usize>,
+        right: Vec<usize>,
+    },
+    Block {
+        from: usize,
+        to: usize,
+    },
+    HealPartition,
+    ClientDelay {
+        client: usize,
+        latency_ms: u64,
+    },
+    ClientTruncate {
+        client: usize,
+        after_bytes: usize,
+    },
+    ClientStall {
+        client: usize,
+    },
+    ClientBlackhole {
+        client: usize,
+    },
+    HealClient {
+        client: usize,
+    },
+    // changes the replicas of every stream of the workload
+    ScaleReplicas {
+        replicas: usize,
+    },
+    Op {
+        client: usize,
+        #[serde(flatten)]
+        op: Op,
+    },
+}
+
+impl Action {
+    // true for every action that injects a fault,
+    // as opposed to healing one or running the workload.
+    pub fn is_fault(&self) -> bool {
+        matches!(
+            self,
+            Action::Restart { .. }
+                | Action::Pause { .. }
+                | Action::Isolate { .. }
+                | Action::Split { .. }
+                | Action::Block { .. }
+                | Action::ClientDelay { .. }
+                | Action::ClientTruncate { .. }
+                | Action::ClientStall { .. }
+                | Action::ClientBlackhole { .. }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Outcome {
+    Ok,
+    Published { seq: u64 },
+    Consumed { seq: u64, value: u64 },
+    // the stream sequences of the fetched messages
+    Fetched { seqs: Vec<u64> },
+    // a key's revision and value, None if deleted
+    Read { revision: u64, value: Option<u64> },
+    Failed { error: String },
+    // a conditional write whose condition didn't hold
+    Conflict { error: String },
+    Indeterminate { error: String },
+}
+
+impl Outcome {
+    // true if an operation definitely went through,
+    // which is what counts as progress.
+    pub fn is_success(&self) -> bool {
+        !matches!(self, Outcome::Failed { .. } | Outcome::Indeterminate { .. })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    pub step: u64,
+    // time since the workload started
+    #[serde(with = "micros")]
+    pub at: Duration,
+    #[serde(flatten)]
+    pub action: Action,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+// appends one JSON object per line, flushing after every
+// event so that nothing is lost when we exit on a violation.
+#[derive(Debug)]
+pub(crate) struct EventLog {
+    writer: LineWriter<File>,
+}
+
+impl EventLog {
+    pub(crate) fn create<P: AsRef<Path>>(path: P) -> io::Result<EventLog> {
+        Ok(EventLog {
+            writer: LineWriter::new(File::create(path)?),
+        })
+    }
+
+    pub(crate) fn record(&mut self, event: &Event) -> io::Result<()> {
+        let line = serde_json::to_string(event)?;
+        writeln!(self.writer, "{}", line)
+    }
+}
+
+// the log of a run derived from another one, which must
+// not overwrite the events it was derived from.
+pub(crate) fn derived_log<P: AsRef<Path>>(path: P, suffix: &str) -> PathBuf {
+    let mut derived = path.as_ref().as_os_str().to_owned();
+    derived.push(suffix);
+    derived.into()
+}
+
+/// Reads back the events written by a previous run.
+pub fn read_events<P: AsRef<Path>>(path: P) -> io::Result<Vec<Event>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut events = vec![];
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        events.push(serde_json::from_str(&line)?);
+    }
+
+    Ok(events)
+}
+
+/// Writes events in the same format as the log of a run.
+pub fn write_events<P: AsRef<Path>>(path: P, events: &[Event]) -> io::Result<()> {
+    let mut log = EventLog::create(path)?;
+    for event in events {
+        log.record(event)?;
+    }
+    Ok(())
+}
+
+mod micros {
+    use std::convert::TryInto;
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(d.as_micros().try_into().unwrap_or(u64::MAX))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        u64::deserialize(d).map(Duration::from_micros)
+    }
+}
+
+#[test]
+fn event_roundtrip() {
+    let events = vec![
+        Event {
+            step: 1,
+            at: Duration::from_micros(1500),
+            action: Action::Split {
+                left: vec![2],
+                right: vec![0, 1],
+            },
+            outcome: Outcome::Ok,
+        },
+        Event {
+            step: 2,
+            at: Duration::from_millis(3),
+            action: Action::HealPartition,
+            outcome: Outcome::Ok,
+        },
+        Event {
+            step: 3,
+            at: Duration::from_secs(1),
+            action: Action::Op {
+                client: 1,
+                op: Op::Consume { stream: 1 },
+            },
+            outcome: Outcome::Consumed { seq: 7, value: 42 },
+        },
+        Event {
+            step: 4,
+            at: Duration::from_secs(2),
+            action: Action::Op {
+                client: 0,
+                op: Op::Publish {
+                    value: 43,
+                    stream: 0,
+                    subject: 2,
+                },
+            },
+            outcome: Outcome::Indeterminate {
+                error: "timed out".into(),
+            },
+        },
+        Event {
+            step: 5,
+            at: Duration::from_secs(3),
+            action: Action::ClientTruncate {
+                client: 2,
+                after_bytes: 17,
+            },
+            outcome: Outcome::Ok,
+        },
+    ];
+
+    for event in events {
+        let line = serde_json::to_string(&event).unwrap();
+        let parsed: Event = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed, event, "{}", line);
+    }
+
+    // logs from before multiple streams still parse
+    let op: Op = serde_json::from_str(r#"{"op":"publish","value":43}"#).unwrap();
+    assert_eq!(
+        op,
+        Op::Publish {
+            value: 43,
+            stream: 0,
+            subject: 0,
+        }
+    );
+}
@@ -0,0 +1,202 @@
+use std::time::Duration;
+
+use crate::{schedule, Action, Args, Cluster, Event, Violation};
+
+// how many times the original schedule is replayed
+// before we give up on reproducing its violation.
+const REPRODUCE_ATTEMPTS: usize = 3;
+
+/// Repeatedly replays reduced versions of a failing schedule
+/// against fresh clusters, and returns the smallest one found
+/// that still fails the same check, along with its violation.
+///
+/// Panics if the original schedule never fails when replayed.
+pub fn shrink(args: &Args, events: Vec<Event>) -> (Vec<Event>, Violation) {
+    let mut args = args.clone();
+    args.event_log = Some(match &args.shrink {
+        Some(path) => schedule::derived_log(path, ".trial"),
+        None => "shrink_trial_events.jsonl".into(),
+    });
+
+    let mut violation = (0..REPRODUCE_ATTEMPTS)
+        .find_map(|_| trial(&args, &events))
+        .expect("the schedule to shrink did not fail when replayed");
+
+    let mut best = truncate(events, &violation);
+
+    println!(
+        "reproduced violation at step {}, shrinking {} events",
+        violation.step,
+        best.len()
+    );
+
+    'outer: loop {
+        for removal in removals(&best) {
+            let candidate = without(&best, &removal);
+            if let Some(found) = trial(&args, &candidate) {
+                if found.same_check(&violation) {
+                    best = truncate(candidate, &found);
+                    violation = found;
+                    println!("shrunk failing schedule to {} events", best.len());
+                    continue 'outer;
+                }
+            }
+        }
+
+        return (best, violation);
+    }
+}
+
+// replays the events against a fresh cluster
+fn trial(args: &Args, events: &[Event]) -> Option<Violation> {
+    let mut cluster = Cluster::start(args.clone());
+    let ret = cluster
+        .replay(events.to_vec())
+        .and_then(|_| cluster.finish());
+    drop(cluster);
+
+    // give the proxies of the dropped cluster a
+    // moment to release their ports.
+    std::thread::sleep(Duration::from_millis(100));
+
+    ret.err()
+}
+
+// drops every event after the one that exposed the violation
+fn truncate(mut events: Vec<Event>, violation: &Violation) -> Vec<Event> {
+    events.retain(|event| event.step <= violation.step);
+    events
+}
+
+// the sorted indices of events to drop from the schedule,
+// roughly from the most to the least aggressive reduction.
+fn removals(events: &[Event]) -> Vec<Vec<usize>> {
+    let is_workload = |action: &Action| matches!(action, Action::Op { .. });
+
+    let faults: Vec<usize> = (0..events.len())
+        .filter(|i| !is_workload(&events[*i].action))
+        .collect();
+
+    let operations: Vec<usize> = (0..events.len())
+        .filter(|i| is_workload(&events[*i].action))
+        .collect();
+
+    let mut removals = vec![];
+
+    for indices in &[faults, operations] {
+        let mut chunk_size = indices.len() / 2;
+        while chunk_size > 0 {
+            removals.extend(indices.chunks(chunk_size).map(<[usize]>::to_vec));
+            chunk_size /= 2;
+        }
+    }
+
+    removals.extend(merged_pauses(events));
+
+    removals
+}
+
+fn without(events: &[Event], indices: &[usize]) -> Vec<Event> {
+    events
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| indices.binary_search(i).is_err())
+        .map(|(_, event)| event.clone())
+        .collect()
+}
+
+// turns a pause, resume, pause, resume sequence of the same
+// server into one longer pause by dropping the inner resume
+// and pause, for every such sequence.
+fn merged_pauses(events: &[Event]) -> Vec<Vec<usize>> {
+    let mut removals = vec![];
+
+    for (resume_idx, event) in events.iter().enumerate() {
+        let server = if let Action::Resume { server } = event.action {
+            server
+        } else {
+            continue;
+        };
+
+        let next_pause = events[resume_idx + 1..]
+            .iter()
+            .position(|event| match event.action {
+                Action::Pause { server: s } | Action::Restart { server: s } => s == server,
+                _ => false,
+            })
+            .map(|offset| resume_idx + 1 + offset);
+
+        if let Some(pause_idx) = next_pause {
+            if let Action::Pause { .. } = events[pause_idx].action {
+                removals.push(vec![resume_idx, pause_idx]);
+            }
+        }
+    }
+
+    removals
+}
+
+#[cfg(test)]
+fn events(actions: Vec<Action>) -> Vec<Event> {
+    actions
+        .into_iter()
+        .enumerate()
+        .map(|(i, action)| Event {
+            step: i as u64 + 1,
+            at: Duration::from_millis(i as u64),
+            action,
+            outcome: crate::Outcome::Ok,
+        })
+        .collect()
+}
+
+#[test]
+fn removal_order() {
+    let op = || Action::Op {
+        client: 0,
+        op: crate::Op::Consume { stream: 0 },
+    };
+    let events = events(vec![
+        Action::Pause { server: 0 },
+        op(),
+        Action::Resume { server: 0 },
+        op(),
+        Action::Restart { server: 1 },
+        Action::HealPartition,
+    ]);
+
+    assert_eq!(
+        removals(&events),
+        vec![
+            // halves, then quarters of the faults
+            vec![0, 2],
+            vec![4, 5],
+            vec![0],
+            vec![2],
+            vec![4],
+            vec![5],
+            // and then of the operations
+            vec![1],
+            vec![3],
+        ]
+    );
+
+    assert_eq!(without(&events, &[0, 2])[0].action, op());
+}
+
+#[test]
+fn pause_merging() {
+    let events = events(vec![
+        Action::Pause { server: 0 },
+        Action::Resume { server: 0 },
+        Action::Pause { server: 1 },
+        Action::Pause { server: 0 },
+        Action::Resume { server: 1 },
+        Action::Restart { server: 1 },
+        Action::Resume { server: 0 },
+    ]);
+
+    // the restart in between doesn't count as a pause,
+    // and the last resume has nothing after it.
+    assert_eq!(merged_pauses(&events), vec![vec![1, 3]]);
+}
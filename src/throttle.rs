@@ -0,0 +1,87 @@
+use rand::Rng;
+
+// after this many consecutive windows without
+// progress, all faults are healed and fault
+// injection is suspended for a whole window.
+const STALLS_BEFORE_HEAL: u32 = 3;
+
+// the most we will ever back off fault injection,
+// as a power of two.
+const MAX_BACKOFF: u32 = 6;
+
+// counts successful operations per window of steps, and
+// backs off fault injection exponentially while the
+// cluster fails to make progress.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    window: u64,
+    steps: u64,
+    successes: u64,
+    stalled_windows: u32,
+    backoff: u32,
+    heal_remaining: u64,
+}
+
+impl Throttle {
+    pub(crate) fn new(window: u64) -> Throttle {
+        Throttle {
+            window,
+            steps: 0,
+            successes: 0,
+            stalled_windows: 0,
+            backoff: 0,
+            heal_remaining: 0,
+        }
+    }
+
+    pub(crate) fn progress(&mut self) {
+        self.successes += 1;
+    }
+
+    // decides whether a fault chosen by the scheduler
+    // should actually be injected.
+    pub(crate) fn admit_fault<R: Rng>(&self, rng: &mut R) -> bool {
+        if self.heal_remaining > 0 {
+            return false;
+        }
+        rng.gen_ratio(1, 1 << self.backoff)
+    }
+
+    // called after every step. returns true when
+    // the cluster should be healed immediately.
+    pub(crate) fn tick(&mut self) -> bool {
+        self.heal_remaining = self.heal_remaining.saturating_sub(1);
+        self.steps += 1;
+
+        if self.steps < self.window {
+            return false;
+        }
+
+        let successes = std::mem::take(&mut self.successes);
+        self.steps = 0;
+
+        if successes > 0 {
+            self.stalled_windows = 0;
+            self.backoff = self.backoff.saturating_sub(1);
+            return false;
+        }
+
+        self.stalled_windows += 1;
+        self.backoff = (self.backoff + 1).min(MAX_BACKOFF);
+
+        println!(
+            "no progress for {} window(s) of {} steps, backing off faults to 1/{}",
+            self.stalled_windows,
+            self.window,
+            1 << self.backoff
+        );
+
+        if self.stalled_windows >= STALLS_BEFORE_HEAL {
+            self.stalled_windows = 0;
+            self.heal_remaining = self.window;
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::fmt;
+use std::time::Duration;
+
+/// A correctness or liveness violation found by one
+/// of the checkers, along with everything needed to
+/// report and reproduce it.
+#[derive(Debug, Clone)]
+pub struct Violation {
+    // e.g. "Correctness" or "Liveness"
+    pub kind: &'static str,
+    pub summary: String,
+    pub details: Vec<(&'static str, String)>,
+    pub elapsed: Duration,
+    pub step: u64,
+    pub seed: u64,
+}
+
+impl Violation {
+    pub(crate) fn correctness(summary: &str) -> Violation {
+        Violation::new("Correctness", summary)
+    }
+
+    pub(crate) fn liveness(summary: &str) -> Violation {
+        Violation::new("Liveness", summary)
+    }
+
+    fn new(kind: &'static str, summary: &str) -> Violation {
+        Violation {
+            kind,
+            summary: summary.to_string(),
+            details: vec![],
+            elapsed: Duration::default(),
+            step: 0,
+            seed: 0,
+        }
+    }
+
+    pub(crate) fn detail<D: fmt::Debug>(mut self, name: &'static str, value: D) -> Violation {
+        self.details.push((name, format!("{:?}", value)));
+        self
+    }
+
+    // true if both violations were found by the same check,
+    // regardless of the particular values involved.
+    pub fn same_check(&self, other: &Violation) -> bool {
+        self.kind == other.kind && self.summary == other.summary
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} violation detected after running for {:?}.",
+            self.kind, self.elapsed
+        )?;
+        writeln!(f, "{}", self.summary)?;
+        for (name, value) in &self.details {
+            writeln!(f, "    {}: {}", name, value)?;
+        }
+        writeln!(f, "    step: {}", self.step)?;
+        write!(f, "    schedule replay seed: {}", self.seed)
+    }
+}
@@ -0,0 +1,220 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
+
+use crate::{Args, Client, Outcome, Violation};
+
+mod chain;
+mod dedup;
+mod kv;
+mod mirror;
+mod pull;
+mod stream;
+
+use chain::ChainWorkload;
+use dedup::DedupWorkload;
+use kv::KvWorkload;
+use mirror::MirrorWorkload;
+use pull::PullWorkload;
+use stream::StreamWorkload;
+
+/// A single client operation of a workload, with every
+/// random choice already resolved so that it can be
+/// re-executed verbatim from an event log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Op {
+    // publishes a value to a subject of a stream, both
+    // by index into the ones the workload set up
+    Publish {
+        value: u64,
+        #[serde(default)]
+        stream: usize,
+        #[serde(default)]
+        subject: usize,
+    },
+    // publishes a value again, with the same message id
+    Republish {
+        value: u64,
+    },
+    Consume {
+        #[serde(default)]
+        stream: usize,
+    },
+    Put {
+        key: usize,
+        value: u64,
+    },
+    Get {
+        key: usize,
+    },
+    Delete {
+        key: usize,
+    },
+    CompareAndSet {
+        key: usize,
+        revision: u64,
+        value: u64,
+    },
+    // fetches up to one message per disposition, and
+    // settles each fetched message accordingly
+    Fetch {
+        dispositions: Vec<Disposition>,
+    },
+    // publishes a value to a chain, expecting its last
+    // sequence to be `expected`
+    Append {
+        chain: usize,
+        expected: u64,
+        value: u64,
+    },
+    ReadLast {
+        chain: usize,
+    },
+    // changes the limits and replicas of a stream,
+    // with None meaning unlimited
+    UpdateStream {
+        stream: usize,
+        max_msgs: Option<u64>,
+        max_age_ms: Option<u64>,
+        replicas: usize,
+    },
+    PurgeStream {
+        stream: usize,
+    },
+    // deletes the client's consumer of a stream
+    // and creates it again
+    RecreateConsumer {
+        stream: usize,
+    },
+}
+
+/// What a client does with a message it fetched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Disposition {
+    Ack,
+    Nak,
+    Term,
+    // let the ack wait expire
+    Ignore,
+}
+
+// when a workload is asked to check its model
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Check {
+    // after every step, for whatever the last
+    // operations revealed
+    Step,
+    // once every client made progress after a heal,
+    // when the cluster should be fully consistent again
+    Recovered,
+    // right after the driver scaled the workload's streams,
+    // when the streams have to be read back to catch writes
+    // lost in the transition
+    Scaled,
+    // at the end of the run, after healing all faults
+    // and waiting out the recovery deadline
+    Final,
+}
+
+// the client-facing half of a run: what JetStream state
+// it needs, which operations the clients perform, and
+// which invariants their results must uphold. the cluster
+// driver takes care of servers, faults and liveness.
+pub(crate) trait Workload {
+    // creates streams, consumers etc. once all clients
+    // are connected. called again if it fails, so it has
+    // to start over from scratch.
+    fn setup(&mut self, clients: &[Client], args: &Args) -> io::Result<()>;
+
+    // chooses the next operation, without any side effects
+    // so that the choice can be replayed from a log.
+    fn step(&mut self, rng: &mut StdRng) -> Op;
+
+    // performs an operation on behalf of a client.
+    fn apply(&mut self, client: &Client, op: &Op) -> Outcome;
+
+    fn check(&mut self, clients: &[Client], check: Check) -> Result<(), Violation>;
+
+    // the names of the streams the workload uses, whose
+    // replicas the cluster driver may change.
+    fn streams(&self) -> Vec<String>;
+}
+
+// how long the streams may take to become readable again
+// after the driver scaled them.
+const SCALED_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub(crate) const WORKLOADS: &[&str] = &["stream", "kv", "pull", "dedup", "chain", "mirror"];
+
+pub(crate) fn by_name(name: &str) -> Option<Box<dyn Workload>> {
+    match name {
+        "stream" => Some(Box::new(StreamWorkload::default())),
+        "kv" => Some(Box::new(KvWorkload::default())),
+        "pull" => Some(Box::new(PullWorkload::default())),
+        "dedup" => Some(Box::new(DedupWorkload::default())),
+        "chain" => Some(Box::new(ChainWorkload::default())),
+        "mirror" => Some(Box::new(MirrorWorkload::default())),
+        _ => None,
+    }
+}
+
+// reads back what a checkpoint checks. checkpoints after a
+// heal are best-effort, it's up to the liveness checker to
+// flag an unavailable stream, and return None if the read
+// failed. after scaling, the read is retried until it
+// succeeds, as skipping it could hide lost writes.
+pub(crate) fn checkpoint<T>(
+    check: Check,
+    mut read: impl FnMut() -> io::Result<T>,
+) -> Result<Option<T>, Violation> {
+    let started = Instant::now();
+    loop {
+        match read() {
+            Ok(contents) => return Ok(Some(contents)),
+            Err(e) if check != Check::Scaled => {
+                println!("skipping checkpoint: {:?}", e);
+                return Ok(None);
+            }
+            Err(e) if started.elapsed() > SCALED_READ_TIMEOUT => {
+                return Err(Violation::liveness(
+                    "The streams could not be read back after scaling them.",
+                )
+                .detail("error", e)
+                .detail("timeout", SCALED_READ_TIMEOUT))
+            }
+            Err(e) => println!("couldn't read back the scaled streams yet: {:?}", e),
+        }
+
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+// reads the streams back for the final check, which has
+// to succeed within a few attempts once all faults are healed.
+pub(crate) fn read_back<T>(
+    streams: Vec<String>,
+    mut read: impl FnMut() -> io::Result<T>,
+) -> Result<T, Violation> {
+    let mut attempts = 1;
+    loop {
+        match read() {
+            Ok(contents) => return Ok(contents),
+            Err(e) if attempts < 3 => {
+                println!("couldn't read back {:?}: {:?}", streams, e);
+                attempts += 1;
+                std::thread::sleep(Duration::from_millis(500));
+            }
+            Err(e) => {
+                return Err(Violation::liveness(
+                    "The streams could not be read back after healing all faults.",
+                )
+                .detail("streams", streams)
+                .detail("error", e))
+            }
+        }
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryInto;
+use std::io;
+use std::time::Duration;
+
+use rand::{rngs::StdRng, Rng};
+
+use nats::jetstream::StreamConfig;
+
+use super::{Check, Op, Workload};
+use crate::durability::{DurabilityModel, Write};
+use crate::jetstream::{self, PublishError};
+use crate::{Args, Client, Outcome, Violation, PUBLISH_TIMEOUT};
+
+const STREAM: &str = "exercise_chain_stream";
+
+// chain 0 spans the whole stream and is appended to with
+// `Nats-Expected-Last-Sequence`, every other chain is a
+// subject of its own appended to with
+// `Nats-Expected-Last-Subject-Sequence`.
+const CHAINS: usize = 4;
+
+const READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+// the first link of every subject chain carries a value of
+// its own above any that `idgen` hands out, since a replay
+// appends the values recorded in its events while the
+// generator keeps counting across the clusters of a shrink.
+const SETUP_VALUES: u64 = 1 << 63;
+
+// every client appends unique values to chains of messages,
+// expecting the last sequence of the chain it knows of, which
+// is sometimes deliberately outdated. a message records the
+// sequence it expected to follow, and the stream must never
+// contain two messages of a chain that claim to follow the
+// same one, nor one that doesn't follow the one it claims.
+#[derive(Default)]
+pub(crate) struct ChainWorkload {
+    // the last sequence we learned of for every chain,
+    // and the one before it
+    last: [u64; CHAINS],
+    previous: [u64; CHAINS],
+    // chains whose last sequence we have to read again,
+    // since a write we didn't learn the sequence of may
+    // have landed
+    stale: BTreeSet<usize>,
+    // (chain, expected sequence) -> the sequence that
+    // JetStream acknowledged an append with it at
+    acked_claims: HashMap<(usize, u64), u64>,
+    unvalidated_claims: Vec<(usize, u64, u64, u64)>,
+    durability_model: DurabilityModel,
+    conflicts: usize,
+}
+
+// what every message in the stream carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Link {
+    value: u64,
+    chain: usize,
+    // 0 for the first message of a subject chain,
+    // which is published without expectations
+    expected: u64,
+}
+
+impl Link {
+    fn encode(&self) -> Vec<u8> {
+        [self.value, self.chain as u64, self.expected]
+            .iter()
+            .flat_map(|n| n.to_le_bytes())
+            .collect()
+    }
+
+    fn decode(data: &[u8]) -> Option<Link> {
+        if data.len() != 24 {
+            return None;
+        }
+        let n = |i: usize| u64::from_le_bytes(data[i * 8..(i + 1) * 8].try_into().unwrap());
+        Some(Link {
+            value: n(0),
+            chain: n(1) as usize,
+            expected: n(2),
+        })
+    }
+}
+
+fn subject(chain: usize) -> String {
+    format!("exercise_chain.{}", chain)
+}
+
+fn header(chain: usize) -> &'static str {
+    if chain == 0 {
+        "Nats-Expected-Last-Sequence"
+    } else {
+        "Nats-Expected-Last-Subject-Sequence"
+    }
+}
+
+impl Workload for ChainWorkload {
+    fn setup(&mut self, clients: &[Client], args: &Args) -> io::Result<()> {
+        println!("creating testing stream {}", STREAM);
+
+        let nc = &clients[0].nc;
+
+        let _ = nc.delete_stream(STREAM);
+        *self = ChainWorkload::default();
+
+        nc.create_stream(StreamConfig {
+            num_replicas: args.num_replicas,
+            name: STREAM.to_string(),
+            subjects: Some(vec!["exercise_chain.>".to_string()]),
+            ..Default::default()
+        })?;
+
+        // servers ignore an expected sequence of 0, so every
+        // subject chain starts with an unconditional message.
+        for chain in 1..CHAINS {
+            let link = Link {
+                value: SETUP_VALUES + chain as u64,
+                chain,
+                expected: 0,
+            };
+            let seq = jetstream::publish(nc, &subject(chain), &link.encode(), PUBLISH_TIMEOUT)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+
+            self.durability_model.write(link.value, Write::Acked(seq));
+            self.last[chain] = seq;
+            self.last[0] = seq;
+        }
+
+        Ok(())
+    }
+
+    fn step(&mut self, rng: &mut StdRng) -> Op {
+        let chain = rng.gen_range(0..CHAINS);
+
+        if self.stale.contains(&chain) {
+            return Op::ReadLast { chain };
+        }
+
+        // an outdated expectation has to conflict
+        let expected = if self.previous[chain] != 0 && rng.gen_ratio(1, 5) {
+            self.previous[chain]
+        } else {
+            self.last[chain]
+        };
+
+        Op::Append {
+            chain,
+            expected,
+            value: crate::idgen(),
+        }
+    }
+
+    fn apply(&mut self, client: &Client, op: &Op) -> Outcome {
+        match *op {
+            Op::Append {
+                chain,
+                expected,
+                value,
+            } => self.append(client, chain, expected, value),
+            Op::ReadLast { chain } => self.read_last(client, chain),
+            ref other => panic!("the chain workload can't apply {:?}", other),
+        }
+    }
+
+    fn check(&mut self, clients: &[Client], check: Check) -> Result<(), Violation> {
+        match check {
+            Check::Step => self.validate(),
+            Check::Recovered | Check::Scaled => {
+                match super::checkpoint(check, || jetstream::messages(&clients[0].nc, STREAM))? {
+                    Some(stream) => self.check_stream(&stream),
+                    None => Ok(()),
+                }
+            }
+            Check::Final => {
+                self.validate()?;
+
+                let stream = super::read_back(self.streams(), || {
+                    jetstream::messages(&clients[0].nc, STREAM)
+                })?;
+
+                self.check_stream(&stream)?;
+
+                println!(
+                    "all {} messages follow the sequence they expected, \
+                    after {} acknowledged appends and {} conflicts",
+                    stream.len(),
+                    self.acked_claims.len(),
+                    self.conflicts
+                );
+
+                Ok(())
+            }
+        }
+    }
+
+    fn streams(&self) -> Vec<String> {
+        vec![STREAM.to_string()]
+    }
+}
+
+impl ChainWorkload {
+    fn append(&mut self, client: &Client, chain: usize, expected: u64, value: u64) -> Outcome {
+        let link = Link {
+            value,
+            chain,
+            expected,
+        };
+        let expected_header = expected.to_string();
+        let headers = jetstream::headers(&[(header(chain), &expected_header)]);
+
+        match jetstream::publish_with_headers(
+            &client.nc,
+            &subject(chain),
+            &headers,
+            &link.encode(),
+            PUBLISH_TIMEOUT,
+        ) {
+            Ok(seq) => {
+                self.durability_model.write(value, Write::Acked(seq));
+
+                if let Some(first_seq) = self.acked_claims.insert((chain, expected), seq) {
+                    self.unvalidated_claims
+                        .push((chain, expected, first_seq, seq));
+                }
+
+                // every append grows the stream chain
+                for chain in [0, chain] {
+                    if seq > self.last[chain] {
+                        self.previous[chain] = self.last[chain];
+                        self.last[chain] = seq;
+                    }
+                }
+
+                Outcome::Published { seq }
+            }
+            Err(e @ PublishError::Conflict(_)) => {
+                self.durability_model.write(value, Write::Failed);
+                self.conflicts += 1;
+                self.stale.insert(chain);
+                Outcome::Conflict {
+                    error: e.to_string(),
+                }
+            }
+            Err(e @ PublishError::Rejected(_)) => {
+                self.durability_model.write(value, Write::Failed);
+                Outcome::Failed {
+                    error: e.to_string(),
+                }
+            }
+            Err(e @ PublishError::Indeterminate(_)) => {
+                self.durability_model.write(value, Write::Indeterminate);
+                self.stale.insert(chain);
+                self.stale.insert(0);
+                Outcome::Indeterminate {
+                    error: e.to_string(),
+                }
+            }
+        }
+    }
+
+    fn read_last(&mut self, client: &Client, chain: usize) -> Outcome {
+        let read = if chain == 0 {
+            client
+                .nc
+                .stream_info(STREAM)
+                .map(|info| (info.state.last_seq, None))
+        } else {
+            jetstream::last_message(&client.nc, STREAM, &subject(chain), READ_TIMEOUT).map(
+                |message| match message {
+                    Some(message) => (
+                        message.seq,
+                        Link::decode(&message.data).map(|link| link.value),
+                    ),
+                    None => (0, None),
+                },
+            )
+        };
+
+        match read {
+            Ok((seq, value)) => {
+                if seq != self.last[chain] {
+                    self.previous[chain] = self.last[chain];
+                    self.last[chain] = seq;
+                }
+                self.stale.remove(&chain);
+                Outcome::Read {
+                    revision: seq,
+                    value,
+                }
+            }
+            Err(e) => Outcome::Failed {
+                error: e.to_string(),
+            },
+        }
+    }
+
+    fn validate(&mut self) -> Result<(), Violation> {
+        if let Some((chain, expected, first_seq, seq)) = self.unvalidated_claims.pop() {
+            return Err(Violation::correctness(
+                "JetStream acknowledged two appends that expected \
+                the same last sequence.",
+            )
+            .detail("subject", subject(chain))
+            .detail("header", header(chain))
+            .detail("expected last sequence", expected)
+            .detail("first acknowledged stream sequence", first_seq)
+            .detail("second acknowledged stream sequence", seq));
+        }
+
+        self.durability_model.validate_acks()
+    }
+
+    fn check_stream(
+        &self,
+        stream: &BTreeMap<u64, jetstream::StoredMessage>,
+    ) -> Result<(), Violation> {
+        let mut links = BTreeMap::new();
+        for (seq, message) in stream {
+            match Link::decode(&message.data) {
+                Some(link) if link.chain < CHAINS && message.subject == subject(link.chain) => {
+                    links.insert(*seq, link);
+                }
+                _ => {
+                    return Err(Violation::correctness(
+                        "The stream contains a message that no client published.",
+                    )
+                    .detail("stream sequence", seq)
+                    .detail("subject", &message.subject)
+                    .detail("data", &message.data))
+                }
+            }
+        }
+
+        let values = links.iter().map(|(seq, link)| (*seq, link.value)).collect();
+        self.durability_model.check_stream(&values)?;
+
+        // (chain, expected sequence) -> the message claiming it
+        let mut claims = HashMap::new();
+        let mut last = [0; CHAINS];
+
+        for (seq, link) in &links {
+            if link.expected != 0 {
+                if let Some(first_seq) = claims.insert((link.chain, link.expected), *seq) {
+                    return Err(Violation::correctness(
+                        "The stream contains two messages that both claim \
+                        to follow the same prior sequence.",
+                    )
+                    .detail("subject", subject(link.chain))
+                    .detail("header", header(link.chain))
+                    .detail("claimed prior sequence", link.expected)
+                    .detail("first stream sequence", first_seq)
+                    .detail("second stream sequence", seq));
+                }
+
+                if last[link.chain] != link.expected {
+                    return Err(Violation::correctness(
+                        "The stream contains a message whose expected \
+                        last sequence did not hold when it was stored.",
+                    )
+                    .detail("subject", subject(link.chain))
+                    .detail("header", header(link.chain))
+                    .detail("stream sequence", seq)
+                    .detail("claimed prior sequence", link.expected)
+                    .detail("actual prior sequence", last[link.chain]));
+                }
+            }
+
+            last[0] = *seq;
+            last[link.chain] = *seq;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn stored(seq: u64, link: Link) -> jetstream::StoredMessage {
+    jetstream::StoredMessage {
+        subject: subject(link.chain),
+        seq,
+        headers: vec![],
+        data: link.encode(),
+    }
+}
+
+#[test]
+fn chain_claims() {
+    let mut workload = ChainWorkload::default();
+    for value in 10..20 {
+        workload.durability_model.write(value, Write::Indeterminate);
+    }
+
+    let link = |value, chain, expected| Link {
+        value,
+        chain,
+        expected,
+    };
+    let mut stream = BTreeMap::new();
+    stream.insert(1, stored(1, link(10, 1, 0)));
+    stream.insert(2, stored(2, link(11, 0, 1)));
+    stream.insert(3, stored(3, link(12, 1, 1)));
+
+    workload.check_stream(&stream).unwrap();
+
+    // a second message following the same one
+    stream.insert(4, stored(4, link(13, 1, 1)));
+    let twice = workload.check_stream(&stream).unwrap_err();
+    assert!(twice.summary.contains("same prior sequence"), "{}", twice);
+
+    // a message whose expectation didn't hold
+    stream.insert(4, stored(4, link(13, 1, 2)));
+    let broken = workload.check_stream(&stream).unwrap_err();
+    assert!(broken.summary.contains("did not hold"), "{}", broken);
+
+    // and one that isn't a link at all
+    let mut garbage = stored(4, link(13, 1, 3));
+    garbage.data.truncate(8);
+    stream.insert(4, garbage);
+    let unknown = workload.check_stream(&stream).unwrap_err();
+    assert!(
+        unknown.summary.contains("no client published"),
+        "{}",
+        unknown
+    );
+}
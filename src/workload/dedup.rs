@@ -0,0 +1,264 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::time::{Duration, Instant};
+
+use rand::{rngs::StdRng, Rng};
+
+use nats::jetstream::StreamConfig;
+
+use super::{Check, Op, Workload};
+use crate::durability::{DurabilityModel, Write};
+use crate::jetstream::{self, PublishError};
+use crate::{Args, Client, Outcome, Violation, PUBLISH_TIMEOUT};
+
+const STREAM: &str = "exercise_dedup_stream";
+
+// how long JetStream remembers the message ids it stored
+const DUPLICATE_WINDOW: Duration = Duration::from_secs(120);
+
+// how long after its first attempt a value may be published
+// again. well within the duplicate window, so that every
+// copy of a value that is stored twice is a violation.
+const RETRY_WINDOW: Duration = Duration::from_secs(60);
+
+// every client publishes unique values with a message id
+// to a single stream, and publishes them again with the same
+// id, both after a lost acknowledgement, e.g. while a server
+// was paused, and after a successful one. JetStream must
+// store every value at most once, and acknowledge every
+// duplicate at the sequence of the original.
+#[derive(Default)]
+pub(crate) struct DedupWorkload {
+    sent: HashMap<u64, Sent>,
+    // values that may still be published again, in the
+    // order they were first published
+    recent: VecDeque<u64>,
+    durability_model: DurabilityModel,
+    // value -> the seqs of two acknowledgements that disagree
+    unvalidated_mismatches: Vec<(u64, u64, u64)>,
+}
+
+struct Sent {
+    first: Instant,
+    attempts: usize,
+    // what we learned from all attempts so far
+    write: Write,
+}
+
+fn msg_id(value: u64) -> String {
+    format!("exercise-{}", value)
+}
+
+impl Workload for DedupWorkload {
+    fn setup(&mut self, clients: &[Client], args: &Args) -> io::Result<()> {
+        println!("creating testing stream {}", STREAM);
+
+        let nc = &clients[0].nc;
+
+        let _ = nc.delete_stream(STREAM);
+
+        nc.create_stream(StreamConfig {
+            num_replicas: args.num_replicas,
+            name: STREAM.to_string(),
+            duplicate_window: Some(DUPLICATE_WINDOW.as_nanos() as isize),
+            ..Default::default()
+        })?;
+
+        Ok(())
+    }
+
+    fn step(&mut self, rng: &mut StdRng) -> Op {
+        while let Some(value) = self.recent.front() {
+            if self.sent[value].first.elapsed() < RETRY_WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+
+        if !self.recent.is_empty() && rng.gen_ratio(3, 10) {
+            let value = self.recent[rng.gen_range(0..self.recent.len())];
+            return Op::Republish { value };
+        }
+
+        Op::Publish {
+            value: crate::idgen(),
+            stream: 0,
+            subject: 0,
+        }
+    }
+
+    fn apply(&mut self, client: &Client, op: &Op) -> Outcome {
+        match *op {
+            Op::Publish { value, .. } | Op::Republish { value } => self.publish(client, value),
+            ref other => panic!("the dedup workload can't apply {:?}", other),
+        }
+    }
+
+    fn check(&mut self, clients: &[Client], check: Check) -> Result<(), Violation> {
+        match check {
+            Check::Step => self.validate(),
+            Check::Recovered | Check::Scaled => {
+                match super::checkpoint(check, || jetstream::drain(&clients[0].nc, STREAM))? {
+                    Some(stream) => self.check_stream(&stream),
+                    None => Ok(()),
+                }
+            }
+            Check::Final => {
+                self.validate()?;
+
+                let stream =
+                    super::read_back(self.streams(), || jetstream::drain(&clients[0].nc, STREAM))?;
+
+                self.check_stream(&stream)?;
+
+                println!(
+                    "no value out of {} was stored twice, after {} publishes",
+                    self.sent.len(),
+                    self.sent.values().map(|sent| sent.attempts).sum::<usize>()
+                );
+
+                Ok(())
+            }
+        }
+    }
+
+    fn streams(&self) -> Vec<String> {
+        vec![STREAM.to_string()]
+    }
+}
+
+impl DedupWorkload {
+    fn publish(&mut self, client: &Client, value: u64) -> Outcome {
+        let id = msg_id(value);
+        let headers = jetstream::headers(&[("Nats-Msg-Id", &id)]);
+        let ret = jetstream::publish_with_headers(
+            &client.nc,
+            STREAM,
+            &headers,
+            &value.to_le_bytes(),
+            PUBLISH_TIMEOUT,
+        );
+
+        let sent = self.sent.entry(value).or_insert_with(|| Sent {
+            first: Instant::now(),
+            attempts: 0,
+            write: Write::Failed,
+        });
+        let first_attempt = sent.attempts == 0;
+        sent.attempts += 1;
+
+        // an attempt only tells us more than the earlier ones
+        // if they failed, or if it was acknowledged.
+        let (write, outcome) = match ret {
+            Ok(seq) => (Write::Acked(seq), Outcome::Published { seq }),
+            Err(e @ PublishError::Rejected(_)) | Err(e @ PublishError::Conflict(_)) => (
+                Write::Failed,
+                Outcome::Failed {
+                    error: e.to_string(),
+                },
+            ),
+            Err(e @ PublishError::Indeterminate(_)) => (
+                Write::Indeterminate,
+                Outcome::Indeterminate {
+                    error: e.to_string(),
+                },
+            ),
+        };
+
+        match (sent.write, write) {
+            (Write::Acked(first_seq), Write::Acked(seq)) if first_seq != seq => {
+                self.unvalidated_mismatches.push((value, first_seq, seq));
+            }
+            (Write::Acked(_), _) => {}
+            (Write::Indeterminate, Write::Failed) => {}
+            (_, write) => {
+                sent.write = write;
+                self.durability_model.write(value, write);
+            }
+        }
+
+        // a value that definitely wasn't stored is not worth
+        // publishing again, since nothing could deduplicate it.
+        if first_attempt && write != Write::Failed {
+            self.recent.push_back(value);
+        }
+
+        outcome
+    }
+
+    fn validate(&mut self) -> Result<(), Violation> {
+        if let Some((value, first_seq, seq)) = self.unvalidated_mismatches.pop() {
+            return Err(Violation::correctness(
+                "JetStream acknowledged a duplicate publish at a different \
+                stream sequence than the original.",
+            )
+            .detail("message id", msg_id(value))
+            .detail("first acknowledged stream sequence", first_seq)
+            .detail("second acknowledged stream sequence", seq));
+        }
+
+        self.durability_model.validate_acks()
+    }
+
+    fn check_stream(&self, stream: &BTreeMap<u64, u64>) -> Result<(), Violation> {
+        let mut seqs: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+        for (seq, value) in stream {
+            seqs.entry(*value).or_default().push(*seq);
+        }
+
+        // every attempt of a value falls within the retry
+        // window, so the duplicate window covers them all.
+        // values we never published are up to the durability
+        // model to flag.
+        let duplicates: Vec<(String, &Vec<u64>, usize)> = seqs
+            .iter()
+            .filter(|(_, seqs)| seqs.len() > 1)
+            .filter_map(|(value, seqs)| {
+                let sent = self.sent.get(value)?;
+                Some((msg_id(*value), seqs, sent.attempts))
+            })
+            .collect();
+
+        if !duplicates.is_empty() {
+            return Err(Violation::correctness(
+                "JetStream stored messages with the same message id at \
+                different stream sequences within the duplicate window.",
+            )
+            .detail("stream", STREAM)
+            .detail("duplicate window", DUPLICATE_WINDOW)
+            .detail("message ids, stream sequences and publishes", duplicates));
+        }
+
+        self.durability_model.check_stream(stream)
+    }
+}
+
+#[test]
+fn duplicate_message_ids() {
+    let mut workload = DedupWorkload::default();
+    for value in [10, 11] {
+        workload.sent.insert(
+            value,
+            Sent {
+                first: Instant::now(),
+                attempts: 2,
+                write: Write::Indeterminate,
+            },
+        );
+        workload.durability_model.write(value, Write::Indeterminate);
+    }
+
+    let mut stream = BTreeMap::new();
+    stream.insert(1, 10);
+    stream.insert(2, 11);
+    workload.check_stream(&stream).unwrap();
+
+    // the retry of a value was stored again
+    stream.insert(3, 10);
+    let duplicate = workload.check_stream(&stream).unwrap_err();
+    assert!(
+        duplicate.summary.contains("same message id"),
+        "{}",
+        duplicate
+    );
+}
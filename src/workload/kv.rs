@@ -0,0 +1,456 @@
+use std::io;
+use std::time::Duration;
+
+use rand::{rngs::StdRng, Rng};
+
+use super::{Check, Op, Workload};
+use crate::jetstream::{self, PublishError};
+use crate::linearizability::{self, History, Model};
+use crate::{Args, Client, Outcome, Violation, PUBLISH_TIMEOUT};
+
+const BUCKET: &str = "exercise";
+
+// the stream backing the bucket, as named by the KV API
+const STREAM: &str = "KV_exercise";
+
+const KEYS: usize = 5;
+
+// how long a client waits for the value of a key
+const GET_TIMEOUT: Duration = Duration::from_millis(500);
+
+// how long creating the bucket may take
+const CREATE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// every client puts, deletes, compare-and-sets and gets
+// the keys of a single key-value bucket, and the history
+// of every key must be linearizable as a single register.
+#[derive(Default)]
+pub(crate) struct KvWorkload {
+    // the last revision we learned of for every key,
+    // for compare-and-set to expect
+    revisions: [u64; KEYS],
+    history: [History<Call, Ret>; KEYS],
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Call {
+    Put(u64),
+    Delete,
+    CompareAndSet { revision: u64, value: u64 },
+    Get,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Ret {
+    Written(u64),
+    Conflict,
+    Read { revision: u64, value: Option<u64> },
+}
+
+// a key's revision and value, which behaves like a
+// register whose revisions only grow. after a write whose
+// revision we never learned, `known` is unset and the
+// revision is the last one we did learn, which the unknown
+// one must be greater than.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Key {
+    revision: u64,
+    known: bool,
+    value: Option<u64>,
+}
+
+impl Default for Key {
+    fn default() -> Key {
+        Key {
+            revision: 0,
+            known: true,
+            value: None,
+        }
+    }
+}
+
+impl Model for Key {
+    type Call = Call;
+    type Ret = Ret;
+
+    fn step(&self, call: &Call, ret: Option<&Ret>) -> Option<Key> {
+        let written = |revision: u64, value: Option<u64>| {
+            // revisions are stream sequences, which only grow
+            if revision <= self.revision {
+                return None;
+            }
+            Some(Key {
+                revision,
+                known: true,
+                value,
+            })
+        };
+        let unknown = |value: Option<u64>| Key {
+            revision: self.revision,
+            known: false,
+            value,
+        };
+
+        match (*call, ret.copied()) {
+            (Call::Put(value), Some(Ret::Written(revision))) => written(revision, Some(value)),
+            (Call::Put(value), None) => Some(unknown(Some(value))),
+            (Call::Delete, Some(Ret::Written(revision))) => written(revision, None),
+            (Call::Delete, None) => Some(unknown(None)),
+            (Call::CompareAndSet { revision, value }, ret) => {
+                let expected = self.known && self.revision == revision;
+                match ret {
+                    Some(Ret::Written(new_revision)) if expected => {
+                        written(new_revision, Some(value))
+                    }
+                    None if expected => Some(unknown(Some(value))),
+                    Some(Ret::Conflict) if !expected => Some(*self),
+                    _ => None,
+                }
+            }
+            (Call::Get, Some(Ret::Read { revision, value })) => {
+                let consistent = value == self.value
+                    && if self.known {
+                        revision == self.revision
+                    } else {
+                        revision > self.revision
+                    };
+                if consistent {
+                    // reading a write of unknown revision
+                    // tells us what that revision was.
+                    Some(Key {
+                        revision,
+                        known: true,
+                        value,
+                    })
+                } else {
+                    None
+                }
+            }
+            (Call::Get, None) => Some(*self),
+            _ => None,
+        }
+    }
+}
+
+fn subject(key: usize) -> String {
+    format!("$KV.{}.k{}", BUCKET, key)
+}
+
+impl Workload for KvWorkload {
+    fn setup(&mut self, clients: &[Client], args: &Args) -> io::Result<()> {
+        println!("creating testing bucket {}", BUCKET);
+
+        let nc = &clients[0].nc;
+
+        let _ = nc.delete_stream(STREAM);
+
+        // the settings the KV API creates a bucket with,
+        // keeping only the latest revision of every key.
+        let bucket = serde_json::json!({
+            "name": STREAM,
+            "subjects": [format!("$KV.{}.>", BUCKET)],
+            "retention": "limits",
+            "storage": "file",
+            "num_replicas": args.num_replicas,
+            "max_msgs_per_subject": 1,
+            "max_msg_size": -1,
+            "discard": "new",
+            "duplicate_window": Duration::from_secs(120).as_nanos() as u64,
+            "allow_rollup_hdrs": true,
+            "deny_delete": true,
+            "allow_direct": true,
+        });
+        jetstream::create_stream(nc, &bucket, CREATE_TIMEOUT)?;
+
+        Ok(())
+    }
+
+    fn step(&mut self, rng: &mut StdRng) -> Op {
+        let key = rng.gen_range(0..KEYS);
+
+        match rng.gen_range(0..10) {
+            0..=3 => Op::Get { key },
+            4..=6 => Op::Put {
+                key,
+                value: crate::idgen(),
+            },
+            7..=8 => Op::CompareAndSet {
+                key,
+                revision: self.revisions[key],
+                value: crate::idgen(),
+            },
+            9 => Op::Delete { key },
+            _ => unreachable!("impossible choice"),
+        }
+    }
+
+    fn apply(&mut self, client: &Client, op: &Op) -> Outcome {
+        let (key, call) = match *op {
+            Op::Put { key, value } => (key, Call::Put(value)),
+            Op::Delete { key } => (key, Call::Delete),
+            Op::CompareAndSet {
+                key,
+                revision,
+                value,
+            } => (key, Call::CompareAndSet { revision, value }),
+            Op::Get { key } => (key, Call::Get),
+            ref other => panic!("the kv workload can't apply {:?}", other),
+        };
+
+        let id = self.history[key].invoke(call);
+
+        let outcome = match call {
+            Call::Put(value) => written(jetstream::publish(
+                &client.nc,
+                &subject(key),
+                &value.to_le_bytes(),
+                PUBLISH_TIMEOUT,
+            )),
+            Call::Delete => {
+                let headers = jetstream::headers(&[("KV-Operation", "DEL")]);
+                written(jetstream::publish_with_headers(
+                    &client.nc,
+                    &subject(key),
+                    &headers,
+                    &[],
+                    PUBLISH_TIMEOUT,
+                ))
+            }
+            Call::CompareAndSet { revision, value } => {
+                let expected = revision.to_string();
+                let headers =
+                    jetstream::headers(&[("Nats-Expected-Last-Subject-Sequence", &expected)]);
+                written(jetstream::publish_with_headers(
+                    &client.nc,
+                    &subject(key),
+                    &headers,
+                    &value.to_le_bytes(),
+                    PUBLISH_TIMEOUT,
+                ))
+            }
+            Call::Get => get(client, key),
+        };
+
+        let history = &mut self.history[key];
+        match outcome {
+            Outcome::Published { seq } => {
+                history.complete(id, Ret::Written(seq));
+                self.revisions[key] = seq;
+            }
+            Outcome::Conflict { .. } => history.complete(id, Ret::Conflict),
+            Outcome::Read { revision, value } => {
+                history.complete(id, Ret::Read { revision, value });
+                self.revisions[key] = revision;
+            }
+            Outcome::Indeterminate { .. } => history.info(id),
+            _ => history.fail(id),
+        }
+
+        outcome
+    }
+
+    fn check(&mut self, clients: &[Client], check: Check) -> Result<(), Violation> {
+        match check {
+            // the search gets more expensive with every
+            // operation, so we only run it once the cluster
+            // has recovered from a heal.
+            Check::Step => Ok(()),
+            Check::Recovered | Check::Scaled => self.validate(),
+            Check::Final => {
+                // make sure the history ends with a read of
+                // every key from a healed cluster.
+                for key in 0..KEYS {
+                    let client = &clients[key % clients.len()];
+                    let read = (0..3).any(|_| match self.apply(client, &Op::Get { key }) {
+                        Outcome::Read { .. } => true,
+                        outcome => {
+                            println!("couldn't read key {}: {:?}", key, outcome);
+                            false
+                        }
+                    });
+
+                    if !read {
+                        return Err(Violation::liveness(
+                            "A key could not be read after healing all faults.",
+                        )
+                        .detail("bucket", BUCKET)
+                        .detail("key", subject(key)));
+                    }
+                }
+
+                self.validate()?;
+
+                println!(
+                    "histories of all {} keys are linearizable, with {} operations",
+                    KEYS,
+                    self.history.iter().map(History::len).sum::<usize>()
+                );
+
+                Ok(())
+            }
+        }
+    }
+
+    fn streams(&self) -> Vec<String> {
+        vec![STREAM.to_string()]
+    }
+}
+
+impl KvWorkload {
+    fn validate(&self) -> Result<(), Violation> {
+        for (key, history) in self.history.iter().enumerate() {
+            if let Err(operation) = linearizability::check(Key::default(), history) {
+                return Err(Violation::correctness(
+                    "The history of a key is not linearizable as a single register.",
+                )
+                .detail("bucket", BUCKET)
+                .detail("key", subject(key))
+                .detail("operations", history.len())
+                .detail("first operation that could not be linearized", operation));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn written(ret: Result<u64, PublishError>) -> Outcome {
+    match ret {
+        Ok(seq) => Outcome::Published { seq },
+        Err(e @ PublishError::Rejected(_)) => Outcome::Failed {
+            error: e.to_string(),
+        },
+        Err(e @ PublishError::Conflict(_)) => Outcome::Conflict {
+            error: e.to_string(),
+        },
+        Err(e @ PublishError::Indeterminate(_)) => Outcome::Indeterminate {
+            error: e.to_string(),
+        },
+    }
+}
+
+fn get(client: &Client, key: usize) -> Outcome {
+    let message = match jetstream::last_message(&client.nc, STREAM, &subject(key), GET_TIMEOUT) {
+        Ok(message) => message,
+        Err(e) => {
+            return Outcome::Failed {
+                error: e.to_string(),
+            }
+        }
+    };
+
+    let message = match message {
+        Some(message) => message,
+        None => {
+            return Outcome::Read {
+                revision: 0,
+                value: None,
+            }
+        }
+    };
+
+    // deletes are stored as markers that only carry a header
+    let header_block = String::from_utf8_lossy(&message.headers);
+    if header_block.contains("KV-Operation: DEL") || header_block.contains("KV-Operation: PURGE") {
+        return Outcome::Read {
+            revision: message.seq,
+            value: None,
+        };
+    }
+
+    match jetstream::decode(&message.data) {
+        Ok(value) => Outcome::Read {
+            revision: message.seq,
+            value: Some(value),
+        },
+        Err(e) => Outcome::Failed {
+            error: e.to_string(),
+        },
+    }
+}
+
+#[test]
+fn key_revisions() {
+    let mut history = History::default();
+
+    let put = history.invoke(Call::Put(1));
+    history.complete(put, Ret::Written(3));
+    let get = history.invoke(Call::Get);
+    history.complete(
+        get,
+        Ret::Read {
+            revision: 3,
+            value: Some(1),
+        },
+    );
+
+    assert!(linearizability::check(Key::default(), &history).is_ok());
+
+    // revisions are stream sequences, which only grow
+    let put = history.invoke(Call::Put(2));
+    history.complete(put, Ret::Written(2));
+
+    assert!(linearizability::check(Key::default(), &history).is_err());
+}
+
+#[test]
+fn key_compare_and_set() {
+    let mut history = History::default();
+
+    let put = history.invoke(Call::Put(1));
+    history.complete(put, Ret::Written(1));
+    let cas = history.invoke(Call::CompareAndSet {
+        revision: 1,
+        value: 2,
+    });
+    history.complete(cas, Ret::Written(2));
+    let stale = history.invoke(Call::CompareAndSet {
+        revision: 1,
+        value: 3,
+    });
+    history.complete(stale, Ret::Conflict);
+
+    assert!(linearizability::check(Key::default(), &history).is_ok());
+
+    // expecting the current revision can't conflict
+    let cas = history.invoke(Call::CompareAndSet {
+        revision: 2,
+        value: 4,
+    });
+    history.complete(cas, Ret::Conflict);
+
+    assert!(linearizability::check(Key::default(), &history).is_err());
+}
+
+#[test]
+fn key_unknown_revisions() {
+    let mut history = History::default();
+
+    let lost = history.invoke(Call::Put(1));
+    history.info(lost);
+    let delete = history.invoke(Call::Delete);
+    history.complete(delete, Ret::Written(2));
+    let get = history.invoke(Call::Get);
+    history.complete(
+        get,
+        Ret::Read {
+            revision: 2,
+            value: None,
+        },
+    );
+
+    // the lost put took effect before the delete, or never
+    assert!(linearizability::check(Key::default(), &history).is_ok());
+
+    // but not after it, at an older revision
+    let get = history.invoke(Call::Get);
+    history.complete(
+        get,
+        Ret::Read {
+            revision: 1,
+            value: Some(1),
+        },
+    );
+
+    assert!(linearizability::check(Key::default(), &history).is_err());
+}
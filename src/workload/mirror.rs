@@ -0,0 +1,286 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::iter;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+
+use nats::jetstream::StreamConfig;
+
+use super::{Check, Op, Workload};
+use crate::confgen::{self, HUB_DOMAIN};
+use crate::durability::{DurabilityModel, Write};
+use crate::jetstream::{self, PublishError};
+use crate::{Args, Client, Outcome, Violation, PUBLISH_TIMEOUT};
+
+// in the hub's domain
+const ORIGIN: &str = "exercise_mirror_origin";
+const SUBJECT: &str = "exercise_mirror";
+
+// in every leaf node's domain
+const MIRROR: &str = "exercise_mirror_copy";
+const SOURCED: &str = "exercise_mirror_sourced";
+
+// how long requests to a leaf node's domain may take,
+// crossing the leaf connection both ways.
+const API_TIMEOUT: Duration = Duration::from_secs(2);
+
+// how long the leaf copies get to catch up with the
+// origin stream once all faults are healed.
+const CATCH_UP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// every client publishes unique values to a stream in the
+// hub's domain, which every leaf node both mirrors and
+// sources into streams of its own domain over its leaf
+// connection. a copy may lag behind the origin stream, but
+// it must never hold anything but a prefix of it, and it
+// has to catch up with it once all faults are healed.
+#[derive(Default)]
+pub(crate) struct MirrorWorkload {
+    domains: Vec<String>,
+    durability_model: DurabilityModel,
+}
+
+// the leaf copies of the origin stream, read before the
+// origin stream itself, which only ever grows, so that
+// they can't be ahead of what we read of it.
+struct Copies {
+    domain: String,
+    mirror: BTreeMap<u64, u64>,
+    sourced: BTreeMap<u64, u64>,
+}
+
+impl Workload for MirrorWorkload {
+    fn setup(&mut self, clients: &[Client], args: &Args) -> io::Result<()> {
+        println!("creating testing stream {}", ORIGIN);
+
+        let nc = &clients[0].nc;
+
+        self.domains = (0..args.leaves as usize)
+            .map(confgen::leaf_domain)
+            .collect();
+        self.durability_model = DurabilityModel::default();
+
+        for domain in &self.domains {
+            for stream in [MIRROR, SOURCED] {
+                let _ = jetstream::delete_stream_in(nc, domain, stream, API_TIMEOUT);
+            }
+        }
+        let _ = nc.delete_stream(ORIGIN);
+
+        nc.create_stream(StreamConfig {
+            num_replicas: args.num_replicas,
+            name: ORIGIN.to_string(),
+            subjects: Some(vec![SUBJECT.to_string()]),
+            ..Default::default()
+        })?;
+
+        // leaf nodes reach the origin stream through
+        // the hub domain's API.
+        let origin = serde_json::json!({
+            "name": ORIGIN,
+            "external": { "api": jetstream::api(HUB_DOMAIN) },
+        });
+
+        for domain in &self.domains {
+            println!("creating {} and {} in domain {}", MIRROR, SOURCED, domain);
+
+            let mirror = serde_json::json!({ "name": MIRROR, "mirror": origin });
+            jetstream::create_stream_in(nc, domain, &mirror, API_TIMEOUT)?;
+
+            let sourced = serde_json::json!({ "name": SOURCED, "sources": [origin] });
+            jetstream::create_stream_in(nc, domain, &sourced, API_TIMEOUT)?;
+        }
+
+        Ok(())
+    }
+
+    fn step(&mut self, _rng: &mut StdRng) -> Op {
+        Op::Publish {
+            value: crate::idgen(),
+            stream: 0,
+            subject: 0,
+        }
+    }
+
+    fn apply(&mut self, client: &Client, op: &Op) -> Outcome {
+        match *op {
+            Op::Publish { value, .. } => self.publish(client, value),
+            ref other => panic!("the mirror workload can't apply {:?}", other),
+        }
+    }
+
+    fn check(&mut self, clients: &[Client], check: Check) -> Result<(), Violation> {
+        let nc = &clients[0].nc;
+
+        match check {
+            Check::Step => self.durability_model.validate_acks(),
+            Check::Recovered | Check::Scaled => {
+                if let Some((copies, origin)) = super::checkpoint(check, || self.read(nc))? {
+                    self.durability_model.check_stream(&origin)?;
+                    for copy in &copies {
+                        copy.check(&origin)?;
+                    }
+                }
+                Ok(())
+            }
+            Check::Final => {
+                self.durability_model.validate_acks()?;
+                self.catch_up(nc)
+            }
+        }
+    }
+
+    fn streams(&self) -> Vec<String> {
+        vec![ORIGIN.to_string()]
+    }
+}
+
+impl MirrorWorkload {
+    fn publish(&mut self, client: &Client, value: u64) -> Outcome {
+        let data = value.to_le_bytes();
+        match jetstream::publish(&client.nc, SUBJECT, &data, PUBLISH_TIMEOUT) {
+            Ok(seq) => {
+                self.durability_model.write(value, Write::Acked(seq));
+                Outcome::Published { seq }
+            }
+            Err(e @ PublishError::Rejected(_)) | Err(e @ PublishError::Conflict(_)) => {
+                self.durability_model.write(value, Write::Failed);
+                Outcome::Failed {
+                    error: e.to_string(),
+                }
+            }
+            Err(e @ PublishError::Indeterminate(_)) => {
+                self.durability_model.write(value, Write::Indeterminate);
+                Outcome::Indeterminate {
+                    error: e.to_string(),
+                }
+            }
+        }
+    }
+
+    fn read(&self, nc: &nats::Connection) -> io::Result<(Vec<Copies>, BTreeMap<u64, u64>)> {
+        let copies = self
+            .domains
+            .iter()
+            .map(|domain| {
+                Ok(Copies {
+                    domain: domain.clone(),
+                    mirror: jetstream::read_by_seq_in(nc, domain, MIRROR)?,
+                    sourced: jetstream::read_by_seq_in(nc, domain, SOURCED)?,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        let origin = jetstream::read_by_seq(nc, ORIGIN)?;
+
+        Ok((copies, origin))
+    }
+
+    // reads the copies and the origin stream until every copy
+    // holds all of it, flagging any divergence on the way.
+    fn catch_up(&self, nc: &nats::Connection) -> Result<(), Violation> {
+        let started = Instant::now();
+
+        loop {
+            let mut lagging = vec![];
+
+            match self.read(nc) {
+                Ok((copies, origin)) => {
+                    self.durability_model.check_stream(&origin)?;
+
+                    for copy in &copies {
+                        if !copy.check(&origin)? {
+                            lagging.push((
+                                copy.domain.clone(),
+                                copy.mirror.len(),
+                                copy.sourced.len(),
+                            ));
+                        }
+                    }
+
+                    if lagging.is_empty() {
+                        println!(
+                            "all {} leaf nodes hold the {} messages of the origin stream",
+                            copies.len(),
+                            origin.len()
+                        );
+                        return Ok(());
+                    }
+
+                    if started.elapsed() > CATCH_UP_TIMEOUT {
+                        return Err(Violation::liveness(
+                            "Leaf node copies did not catch up with their origin \
+                            stream after healing all faults.",
+                        )
+                        .detail("origin messages", origin.len())
+                        .detail("domains, mirrored and sourced messages", lagging)
+                        .detail("timeout", CATCH_UP_TIMEOUT));
+                    }
+                }
+                Err(e) if started.elapsed() > CATCH_UP_TIMEOUT => {
+                    return Err(Violation::liveness(
+                        "The origin stream and its leaf node copies could not be \
+                        read back after healing all faults.",
+                    )
+                    .detail("error", e));
+                }
+                Err(e) => println!("couldn't read the leaf node copies yet: {:?}", e),
+            }
+
+            std::thread::sleep(Duration::from_millis(500));
+        }
+    }
+}
+
+impl Copies {
+    // returns whether both copies hold all of the origin
+    // stream, or a violation if either one diverged from it.
+    fn check(&self, origin: &BTreeMap<u64, u64>) -> Result<bool, Violation> {
+        // a mirror keeps the sequences of its origin
+        let last_seq = self.mirror.keys().next_back().copied().unwrap_or(0);
+        let expected: BTreeMap<u64, u64> = origin
+            .range(..=last_seq)
+            .map(|(seq, value)| (*seq, *value))
+            .collect();
+
+        if self.mirror != expected {
+            let (seq, mirrored, original) = expected
+                .keys()
+                .chain(self.mirror.keys())
+                .map(|seq| (*seq, self.mirror.get(seq), expected.get(seq)))
+                .find(|(_, mirrored, original)| mirrored != original)
+                .unwrap();
+
+            return Err(Violation::correctness(
+                "A leaf node's mirror diverged from its origin stream.",
+            )
+            .detail("domain", &self.domain)
+            .detail("stream", MIRROR)
+            .detail("stream sequence", seq)
+            .detail("mirrored value", mirrored)
+            .detail("original value", original));
+        }
+
+        // a sourced stream numbers messages on its own, but
+        // stores them in the order of the origin stream
+        let originals = origin.values().map(Some).chain(iter::repeat(None));
+        if let Some(((seq, value), original)) = self
+            .sourced
+            .iter()
+            .zip(originals)
+            .find(|((_, value), original)| *original != Some(*value))
+        {
+            return Err(Violation::correctness(
+                "A leaf node's sourced stream diverged from its origin stream.",
+            )
+            .detail("domain", &self.domain)
+            .detail("stream", SOURCED)
+            .detail("stream sequence", seq)
+            .detail("sourced value", value)
+            .detail("original value", original));
+        }
+
+        Ok(self.mirror.len() == origin.len() && self.sourced.len() == origin.len())
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::mem;
+use std::time::Duration;
+
+use rand::{rngs::StdRng, Rng};
+
+use nats::jetstream::{AckPolicy, ConsumerConfig, RetentionPolicy, StreamConfig};
+
+use super::{Check, Disposition, Op, Workload};
+use crate::delivery::{Delivery, DurableModel};
+use crate::durability::{DurabilityModel, Write};
+use crate::jetstream::{self, PublishError};
+use crate::{Args, Client, Outcome, Violation, PUBLISH_TIMEOUT};
+
+const STREAM: &str = "exercise_pull_stream";
+
+// low enough for messages to actually run out of deliveries
+const MAX_DELIVER: i64 = 5;
+
+// how long the server waits for an acknowledgement
+// before redelivering a message.
+const ACK_WAIT: Duration = Duration::from_secs(1);
+
+const FETCH_TIMEOUT: Duration = Duration::from_millis(500);
+
+// how long a client waits for the server to confirm
+// an acknowledgement.
+const ACK_TIMEOUT: Duration = Duration::from_millis(500);
+
+const MAX_BATCH: usize = 5;
+
+// how often the final drain waits out the ack wait
+// without fetching anything before giving up.
+const DRAIN_ATTEMPTS: usize = 5;
+
+// every client publishes unique values to a single stream
+// and fetches batches from its own durable pull consumer,
+// or from one they all share for a work queue stream,
+// randomly acking, naking, terminating or ignoring every
+// message it fetches.
+#[derive(Default)]
+pub(crate) struct PullWorkload {
+    consumers: Vec<PullConsumer>,
+    unvalidated_consumers: BTreeSet<usize>,
+    durability_model: DurabilityModel,
+    // set for work queue streams
+    shared: bool,
+    // set for interest and work queue streams, which remove
+    // messages once every consumer settled them
+    removes_settled: bool,
+    // stream seq -> consumers that sent an ack or term for it
+    settled_by: HashMap<u64, BTreeSet<usize>>,
+    // messages whose ack was confirmed, which a work queue
+    // stream must not store anymore
+    must_be_removed: BTreeSet<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Settled {
+    Acked,
+    Terminated,
+}
+
+struct PullConsumer {
+    name: String,
+    // deliveries since the last validation, in order, along
+    // with how the server had confirmed settling the message
+    // before it delivered it again, if it had.
+    deliveries: Vec<(Delivery, Option<Settled>)>,
+    model: DurableModel,
+    // messages whose ack or term the server confirmed
+    settled: BTreeMap<u64, Settled>,
+    // stream seq -> delivery count of messages that the
+    // server has to deliver again, because they were naked
+    // or ignored. messages whose ack or term went
+    // unconfirmed may or may not come back.
+    outstanding: BTreeMap<u64, u64>,
+}
+
+impl Workload for PullWorkload {
+    fn setup(&mut self, clients: &[Client], args: &Args) -> io::Result<()> {
+        println!("creating testing stream {}", STREAM);
+
+        let nc = &clients[0].nc;
+
+        let _ = nc.delete_stream(STREAM);
+        self.consumers.clear();
+
+        self.shared = matches!(args.retention, RetentionPolicy::WorkQueue);
+        self.removes_settled = !matches!(args.retention, RetentionPolicy::Limits);
+
+        nc.create_stream(StreamConfig {
+            num_replicas: args.num_replicas,
+            name: STREAM.to_string(),
+            retention: args.retention,
+            ..Default::default()
+        })?;
+
+        // a work queue stream only permits a single
+        // consumer without a filter subject.
+        let owners = if self.shared { &clients[..1] } else { clients };
+
+        for client in owners {
+            let name = format!("pull_consumer_{}", client.id);
+            println!("creating testing pull consumer {}", name);
+
+            client.nc.create_consumer(
+                STREAM,
+                ConsumerConfig {
+                    durable_name: Some(name.clone()),
+                    ack_policy: AckPolicy::Explicit,
+                    ack_wait: Some(ACK_WAIT.as_nanos() as isize),
+                    max_deliver: Some(MAX_DELIVER),
+                    ..Default::default()
+                },
+            )?;
+
+            self.consumers.push(PullConsumer {
+                name,
+                deliveries: Default::default(),
+                model: Default::default(),
+                settled: Default::default(),
+                outstanding: Default::default(),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn step(&mut self, rng: &mut StdRng) -> Op {
+        if rng.gen_ratio(3, 10) {
+            return Op::Publish {
+                value: crate::idgen(),
+                stream: 0,
+                subject: 0,
+            };
+        }
+
+        let dispositions = (0..rng.gen_range(1..=MAX_BATCH))
+            .map(|_| match rng.gen_range(0..100) {
+                0..=69 => Disposition::Ack,
+                70..=79 => Disposition::Nak,
+                80..=84 => Disposition::Term,
+                85..=99 => Disposition::Ignore,
+                _ => unreachable!("impossible choice"),
+            })
+            .collect();
+
+        Op::Fetch { dispositions }
+    }
+
+    fn apply(&mut self, client: &Client, op: &Op) -> Outcome {
+        match op {
+            Op::Publish { value, .. } => self.publish(client, *value),
+            Op::Fetch { dispositions } => self.fetch(client, dispositions),
+            other => panic!("the pull workload can't apply {:?}", other),
+        }
+    }
+
+    fn check(&mut self, clients: &[Client], check: Check) -> Result<(), Violation> {
+        match check {
+            Check::Step => self.validate(),
+            Check::Recovered | Check::Scaled => {
+                match super::checkpoint(check, || self.read_stream(&clients[0]))? {
+                    Some(stream) => self.durability_model.check_stream(&stream),
+                    None => Ok(()),
+                }
+            }
+            Check::Final => self.finish(clients),
+        }
+    }
+
+    fn streams(&self) -> Vec<String> {
+        vec![STREAM.to_string()]
+    }
+}
+
+impl PullWorkload {
+    fn read_stream(&self, client: &Client) -> io::Result<BTreeMap<u64, u64>> {
+        if self.shared {
+            // work queue streams reject the consumer drain needs
+            jetstream::read_by_seq(&client.nc, STREAM)
+        } else {
+            jetstream::drain(&client.nc, STREAM)
+        }
+    }
+
+    fn publish(&mut self, client: &Client, value: u64) -> Outcome {
+        let data = value.to_le_bytes();
+        match jetstream::publish(&client.nc, STREAM, &data, PUBLISH_TIMEOUT) {
+            Ok(seq) => {
+                self.durability_model.write(value, Write::Acked(seq));
+                Outcome::Published { seq }
+            }
+            Err(e @ PublishError::Rejected(_)) | Err(e @ PublishError::Conflict(_)) => {
+                self.durability_model.write(value, Write::Failed);
+                Outcome::Failed {
+                    error: e.to_string(),
+                }
+            }
+            Err(e @ PublishError::Indeterminate(_)) => {
+                self.durability_model.write(value, Write::Indeterminate);
+                Outcome::Indeterminate {
+                    error: e.to_string(),
+                }
+            }
+        }
+    }
+
+    fn consumer(&self, client: usize) -> usize {
+        if self.shared {
+            0
+        } else {
+            client
+        }
+    }
+
+    fn fetch(&mut self, client: &Client, dispositions: &[Disposition]) -> Outcome {
+        let id = self.consumer(client.id);
+        let consumers = self.consumers.len();
+        let c = &mut self.consumers[id];
+
+        let messages = match jetstream::fetch(
+            &client.nc,
+            STREAM,
+            &c.name,
+            dispositions.len(),
+            FETCH_TIMEOUT,
+        ) {
+            Ok(messages) => messages,
+            Err(e) => {
+                return Outcome::Failed {
+                    error: e.to_string(),
+                }
+            }
+        };
+
+        self.unvalidated_consumers.insert(id);
+
+        let mut seqs = vec![];
+
+        for (msg, disposition) in messages.iter().zip(dispositions) {
+            let info = msg.jetstream_message_info().unwrap();
+            let value = match jetstream::decode(&msg.data) {
+                Ok(value) => value,
+                Err(e) => {
+                    return Outcome::Failed {
+                        error: e.to_string(),
+                    }
+                }
+            };
+
+            let delivery = Delivery {
+                stream_seq: info.stream_seq,
+                consumer_seq: info.consumer_seq,
+                delivered: info.delivered as u64,
+                value,
+            };
+            let seq = delivery.stream_seq;
+
+            c.deliveries.push((delivery, c.settled.get(&seq).copied()));
+            c.outstanding.insert(seq, delivery.delivered);
+            seqs.push(seq);
+
+            let (kind, settled) = match disposition {
+                Disposition::Ignore => continue,
+                Disposition::Nak => ("-NAK", None),
+                Disposition::Ack => ("+ACK", Some(Settled::Acked)),
+                Disposition::Term => ("+TERM", Some(Settled::Terminated)),
+            };
+
+            let confirmed = jetstream::ack(&client.nc, msg, kind, ACK_TIMEOUT).is_ok();
+
+            if let Some(settled) = settled {
+                c.outstanding.remove(&seq);
+                if confirmed {
+                    c.settled.insert(seq, settled);
+                }
+                if confirmed && settled == Settled::Acked && self.shared {
+                    self.must_be_removed.insert(seq);
+                }
+
+                let settled_by = self.settled_by.entry(seq).or_default();
+                settled_by.insert(id);
+                if self.removes_settled && settled_by.len() == consumers {
+                    self.durability_model.deleted.insert(seq);
+                }
+            }
+        }
+
+        Outcome::Fetched { seqs }
+    }
+
+    fn validate(&mut self) -> Result<(), Violation> {
+        let unvalidated_consumers = mem::take(&mut self.unvalidated_consumers);
+
+        for id in unvalidated_consumers {
+            let c = &mut self.consumers[id];
+
+            for (delivery, settled) in mem::take(&mut c.deliveries) {
+                if let Some(settled) = settled {
+                    return Err(Violation::correctness(
+                        "A pull consumer redelivered a message after \
+                        confirming that it was settled.",
+                    )
+                    .detail("consumer", &c.name)
+                    .detail("stream sequence", delivery.stream_seq)
+                    .detail("settled as", settled)
+                    .detail("delivery count", delivery.delivered));
+                }
+
+                let deleted = &self.durability_model.deleted;
+                c.model.observe(&c.name, &delivery, MAX_DELIVER, |seq| {
+                    deleted.contains(&seq)
+                })?;
+
+                self.durability_model
+                    .observe(delivery.stream_seq, delivery.value)?;
+            }
+        }
+
+        self.durability_model.validate_acks()
+    }
+
+    // acks everything left in the consumers, and then checks
+    // that every message that had to be delivered again was,
+    // and that no acknowledged write was lost.
+    fn finish(&mut self, clients: &[Client]) -> Result<(), Violation> {
+        let ack_all = Op::Fetch {
+            dispositions: vec![Disposition::Ack; MAX_BATCH],
+        };
+
+        let drainers = if self.shared { &clients[..1] } else { clients };
+
+        for client in drainers {
+            let mut attempts = 0;
+            while attempts < DRAIN_ATTEMPTS {
+                match self.apply(client, &ack_all) {
+                    Outcome::Fetched { seqs } if !seqs.is_empty() => continue,
+                    _ => {
+                        // ignored messages only come back
+                        // once their ack wait expired.
+                        std::thread::sleep(ACK_WAIT);
+                        attempts += 1;
+                    }
+                }
+            }
+        }
+
+        self.validate()?;
+
+        for c in &self.consumers {
+            // fetches drop whatever arrives after they stopped
+            // waiting, which only comes back redelivered.
+            let deleted = &self.durability_model.deleted;
+            c.model
+                .check_drained(&c.name, |seq| deleted.contains(&seq))?;
+
+            let missing: Vec<u64> = c
+                .outstanding
+                .iter()
+                .filter(|(_, delivered)| **delivered < MAX_DELIVER as u64)
+                .map(|(seq, _)| *seq)
+                .collect();
+
+            if !missing.is_empty() {
+                return Err(Violation::correctness(
+                    "A pull consumer never redelivered messages that were \
+                    not acknowledged, although they had deliveries left.",
+                )
+                .detail("consumer", &c.name)
+                .detail("stream sequences", missing)
+                .detail("max deliver", MAX_DELIVER));
+            }
+
+            for seq in self.durability_model.acked().keys() {
+                if !c.model.has_delivered(*seq) && !self.durability_model.deleted.contains(seq) {
+                    return Err(Violation::correctness(
+                        "An acknowledged write was never delivered to a pull consumer.",
+                    )
+                    .detail("consumer", &c.name)
+                    .detail("stream sequence", seq));
+                }
+            }
+        }
+
+        let stream = super::read_back(self.streams(), || self.read_stream(&clients[0]))?;
+
+        self.durability_model.check_stream(&stream)?;
+
+        let still_stored: Vec<u64> = self
+            .must_be_removed
+            .iter()
+            .filter(|seq| stream.contains_key(seq))
+            .copied()
+            .collect();
+
+        if !still_stored.is_empty() {
+            return Err(Violation::correctness(
+                "A work queue stream still stores messages after \
+                confirming their acknowledgement.",
+            )
+            .detail("stream", STREAM)
+            .detail("stream sequences", still_stored));
+        }
+
+        println!(
+            "no acknowledged writes or pending redeliveries were lost out of {}",
+            self.durability_model.acked().len()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,619 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::mem;
+use std::time::Duration;
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng};
+
+use nats::jetstream::{ConsumerConfig, RetentionPolicy, StreamConfig};
+
+use super::{Check, Op, Workload};
+use crate::delivery::{Delivery, DurableModel};
+use crate::durability::{DurabilityModel, Write};
+use crate::jetstream::{self, PublishError};
+use crate::{Args, Client, Outcome, Violation, PUBLISH_TIMEOUT};
+
+// how often a durable consumer may deliver the
+// same message before giving up on it.
+const MAX_DELIVER: i64 = 20;
+
+// how long a durable consumer waits for an ack before it
+// delivers a message again, e.g. one lost in flight.
+const ACK_WAIT: Duration = Duration::from_secs(5);
+
+// how often the final check drains the consumers, waiting
+// for messages they passed over to be redelivered.
+const DRAIN_ATTEMPTS: usize = 5;
+
+// how long a client waits for the server to
+// confirm a stream update.
+const UPDATE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// a message's stream, by index, and its seq in that stream
+type Position = (usize, u64);
+
+// every client publishes unique values to the subjects of
+// one or more streams, and reads every stream back through
+// its own durable push consumer. the first client's
+// consumers see whole streams, while those of the other
+// clients filter on a single subject if there are several.
+// with --config-changes, clients also update the limits
+// and replicas of the streams, purge them, and recreate
+// their consumers.
+#[derive(Default)]
+pub(crate) struct StreamWorkload {
+    streams: usize,
+    subjects: usize,
+    servers: usize,
+    config_changes: bool,
+    // indexed by client * streams + stream
+    consumers: Vec<Consumer>,
+    unvalidated_consumers: BTreeSet<usize>,
+    durability_model: DurabilityModel<Position>,
+    // set for interest streams, which remove messages
+    // once every consumer acknowledged them
+    interest: bool,
+    // position -> consumers that received it
+    delivered_to: HashMap<Position, BTreeSet<usize>>,
+    // position -> the subject of the message there,
+    // for those we published or received
+    subject_of: HashMap<Position, usize>,
+    // stream -> subject -> consumers that receive it
+    interested: Vec<Vec<usize>>,
+    // set for streams that may have had limits, which
+    // remove messages from the front of the stream at any
+    // time, or that may have been purged at a point we
+    // don't know of
+    limited: Vec<bool>,
+    // stream -> the first seq that survived the last purge
+    purged_below: Vec<u64>,
+}
+
+struct Consumer {
+    nc: nats::Connection,
+    name: String,
+    stream: usize,
+    filter: Option<usize>,
+    // None after deleting it, until it is created again
+    inner: Option<nats::jetstream::Consumer>,
+    generation: usize,
+    // since the last validation, in order
+    received: Vec<Received>,
+    model: DurableModel,
+}
+
+enum Received {
+    // along with the subject it was published to
+    Delivery(Delivery, usize),
+    // the consumer was deleted, and starts over from
+    // the front of the stream once it is created again
+    Deleted,
+}
+
+fn stream_name(stream: usize) -> String {
+    if stream == 0 {
+        "exercise_stream".to_string()
+    } else {
+        format!("exercise_stream_{}", stream)
+    }
+}
+
+fn subject(stream: usize, subject: usize) -> String {
+    format!("{}.{}", stream_name(stream), subject)
+}
+
+impl Consumer {
+    fn create(&mut self) -> io::Result<()> {
+        // every incarnation gets its own deliver subject, so
+        // that it never receives what an earlier one pushed.
+        let deliver_subject = if self.generation == 0 {
+            self.name.clone()
+        } else {
+            format!("{}_{}", self.name, self.generation)
+        };
+
+        let conf = ConsumerConfig {
+            deliver_subject: Some(deliver_subject),
+            durable_name: Some(self.name.clone()),
+            max_deliver: Some(MAX_DELIVER),
+            ack_wait: Some(ACK_WAIT.as_nanos() as isize),
+            filter_subject: self.filter.map(|filter| subject(self.stream, filter)),
+            ..Default::default()
+        };
+
+        self.inner = Some(self.nc.create_consumer(stream_name(self.stream), conf)?);
+        Ok(())
+    }
+
+    fn delete(&mut self) -> io::Result<bool> {
+        if self.inner.take().is_some() {
+            self.generation += 1;
+            self.received.push(Received::Deleted);
+        }
+        self.nc
+            .delete_consumer(stream_name(self.stream), &self.name)
+    }
+}
+
+impl Workload for StreamWorkload {
+    fn setup(&mut self, clients: &[Client], args: &Args) -> io::Result<()> {
+        let nc = &clients[0].nc;
+
+        self.consumers.clear();
+        self.streams = args.streams;
+        self.subjects = args.subjects;
+        self.servers = args.servers as usize;
+        self.config_changes = args.config_changes;
+        self.interest = matches!(args.retention, RetentionPolicy::Interest);
+        self.limited = vec![false; self.streams];
+        self.purged_below = vec![0; self.streams];
+
+        for stream in 0..self.streams {
+            let name = stream_name(stream);
+            println!("creating testing stream {}", name);
+
+            let _ = nc.delete_stream(&name);
+
+            let config = StreamConfig {
+                num_replicas: args.num_replicas,
+                subjects: Some(vec![format!("{}.*", name)]),
+                name,
+                retention: args.retention,
+                ..Default::default()
+            };
+            nc.create_stream(config)?;
+        }
+
+        for client in clients {
+            let filter = if client.id == 0 || self.subjects == 1 {
+                None
+            } else {
+                Some((client.id - 1) % self.subjects)
+            };
+
+            for stream in 0..self.streams {
+                let name = if self.streams == 1 {
+                    format!("consumer_{}", client.id)
+                } else {
+                    format!("consumer_{}_{}", client.id, stream)
+                };
+                println!("creating testing consumer {}", name);
+
+                let mut consumer = Consumer {
+                    nc: client.nc.clone(),
+                    name,
+                    stream,
+                    filter,
+                    inner: None,
+                    generation: 0,
+                    received: Default::default(),
+                    model: Default::default(),
+                };
+                consumer.create()?;
+                self.consumers.push(consumer);
+            }
+        }
+
+        self.interested = (0..self.streams)
+            .map(|stream| {
+                (0..self.subjects)
+                    .map(|subject| {
+                        self.consumers
+                            .iter()
+                            .filter(|c| c.stream == stream)
+                            .filter(|c| c.filter.unwrap_or(subject) == subject)
+                            .count()
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Ok(())
+    }
+
+    fn step(&mut self, rng: &mut StdRng) -> Op {
+        let stream = rng.gen_range(0..self.streams);
+
+        if self.config_changes && rng.gen_ratio(1, 50) {
+            return self.change(rng, stream);
+        }
+
+        if rng.gen_ratio(11, 84) {
+            Op::Publish {
+                value: crate::idgen(),
+                stream,
+                subject: rng.gen_range(0..self.subjects),
+            }
+        } else {
+            Op::Consume { stream }
+        }
+    }
+
+    fn apply(&mut self, client: &Client, op: &Op) -> Outcome {
+        match *op {
+            Op::Publish {
+                value,
+                stream,
+                subject,
+            } => self.publish(client, value, stream, subject),
+            Op::Consume { stream } => self.consume(client.id * self.streams + stream),
+            Op::UpdateStream {
+                stream,
+                max_msgs,
+                max_age_ms,
+                replicas,
+            } => self.update(client, stream, max_msgs, max_age_ms, replicas),
+            Op::PurgeStream { stream } => self.purge(client, stream),
+            Op::RecreateConsumer { stream } => {
+                let id = client.id * self.streams + stream;
+                let c = &mut self.consumers[id];
+                self.unvalidated_consumers.insert(id);
+
+                // if the delete fails, so does the create
+                let _ = c.delete();
+                match c.create() {
+                    Ok(()) => Outcome::Ok,
+                    Err(e) => Outcome::Failed {
+                        error: e.to_string(),
+                    },
+                }
+            }
+            ref other => panic!("the stream workload can't apply {:?}", other),
+        }
+    }
+
+    fn check(&mut self, clients: &[Client], check: Check) -> Result<(), Violation> {
+        match check {
+            Check::Step => self.validate(),
+            Check::Recovered | Check::Scaled => {
+                match super::checkpoint(check, || self.read_streams(&clients[0]))? {
+                    Some(streams) => self.durability_model.check_stream(&streams),
+                    None => Ok(()),
+                }
+            }
+            Check::Final => self.finish(clients),
+        }
+    }
+
+    fn streams(&self) -> Vec<String> {
+        (0..self.streams).map(stream_name).collect()
+    }
+}
+
+impl StreamWorkload {
+    // picks a random change to the configuration
+    // of a stream or one of its consumers.
+    fn change(&self, rng: &mut StdRng, stream: usize) -> Op {
+        match rng.gen_range(0..3) {
+            0 => {
+                let replicas: Vec<usize> = [1, 3, 5]
+                    .iter()
+                    .copied()
+                    .filter(|replicas| *replicas <= self.servers)
+                    .collect();
+                Op::UpdateStream {
+                    stream,
+                    max_msgs: *[None, Some(100), Some(1000)].choose(rng).unwrap(),
+                    max_age_ms: *[None, Some(30_000), Some(120_000)].choose(rng).unwrap(),
+                    replicas: *replicas.choose(rng).unwrap_or(&1),
+                }
+            }
+            1 => Op::PurgeStream { stream },
+            // deleting a consumer of an interest stream ends its
+            // interest in messages, which we don't model.
+            _ if self.interest => Op::PurgeStream { stream },
+            _ => Op::RecreateConsumer { stream },
+        }
+    }
+
+    fn publish(&mut self, client: &Client, value: u64, stream: usize, subject: usize) -> Outcome {
+        let data = value.to_le_bytes();
+        let ret = jetstream::publish(
+            &client.nc,
+            &self::subject(stream, subject),
+            &data,
+            PUBLISH_TIMEOUT,
+        );
+        match ret {
+            Ok(seq) => {
+                self.durability_model
+                    .write(value, Write::Acked((stream, seq)));
+                self.subject_of.insert((stream, seq), subject);
+                Outcome::Published { seq }
+            }
+            Err(e @ PublishError::Rejected(_)) | Err(e @ PublishError::Conflict(_)) => {
+                self.durability_model.write(value, Write::Failed);
+                Outcome::Failed {
+                    error: e.to_string(),
+                }
+            }
+            Err(e @ PublishError::Indeterminate(_)) => {
+                self.durability_model.write(value, Write::Indeterminate);
+                Outcome::Indeterminate {
+                    error: e.to_string(),
+                }
+            }
+        }
+    }
+
+    fn update(
+        &mut self,
+        client: &Client,
+        stream: usize,
+        max_msgs: Option<u64>,
+        max_age_ms: Option<u64>,
+        replicas: usize,
+    ) -> Outcome {
+        // the cluster driver may have scaled the stream since
+        // we last updated it, so start from its live config.
+        let mut config = match client.nc.stream_info(stream_name(stream)) {
+            Ok(info) => info.config,
+            Err(e) => {
+                return Outcome::Failed {
+                    error: e.to_string(),
+                }
+            }
+        };
+        config.max_msgs = max_msgs.map_or(-1, |max_msgs| max_msgs as i64);
+        config.max_age = max_age_ms.map_or(0, |ms| Duration::from_millis(ms).as_nanos() as isize);
+        config.num_replicas = replicas;
+
+        // limits may take effect even if we never
+        // learn that the update went through.
+        if max_msgs.is_some() || max_age_ms.is_some() {
+            self.limited[stream] = true;
+        }
+
+        match jetstream::update_stream(&client.nc, &config, UPDATE_TIMEOUT) {
+            Ok(()) => Outcome::Ok,
+            Err(e) => Outcome::Failed {
+                error: e.to_string(),
+            },
+        }
+    }
+
+    fn purge(&mut self, client: &Client, stream: usize) -> Outcome {
+        let name = stream_name(stream);
+
+        let purged = client
+            .nc
+            .purge_stream(&name)
+            .and_then(|_| client.nc.stream_info(&name));
+
+        match purged {
+            Ok(info) => {
+                // everything below the first seq left after the
+                // purge is gone for good, including the writes
+                // we know were acknowledged.
+                let first_seq = info.state.first_seq;
+                self.purged_below[stream] = self.purged_below[stream].max(first_seq);
+                self.remove_below(stream, first_seq);
+                Outcome::Ok
+            }
+            Err(e) => {
+                // the purge may still have happened,
+                // at a point we don't know.
+                self.limited[stream] = true;
+                Outcome::Failed {
+                    error: e.to_string(),
+                }
+            }
+        }
+    }
+
+    // marks every acknowledged write of a stream
+    // below a seq as deleted on purpose.
+    fn remove_below(&mut self, stream: usize, seq: u64) {
+        let removed: Vec<Position> = self
+            .durability_model
+            .acked()
+            .range((stream, 0)..(stream, seq))
+            .map(|(position, _)| *position)
+            .collect();
+        self.durability_model.deleted.extend(removed);
+    }
+
+    fn consume(&mut self, id: usize) -> Outcome {
+        let c = &mut self.consumers[id];
+
+        if c.inner.is_none() {
+            // a deleted consumer that couldn't be created
+            // again right away, whose old incarnation may
+            // still exist.
+            let _ = c.delete();
+            if let Err(e) = c.create() {
+                return Outcome::Failed {
+                    error: e.to_string(),
+                };
+            }
+        }
+
+        let prefix = format!("{}.", stream_name(c.stream));
+        let inner = c.inner.as_mut().unwrap();
+        let proc_ret: io::Result<(Delivery, usize)> = inner.process_timeout(|msg| {
+            let info = msg.jetstream_message_info().unwrap();
+
+            let subject = msg
+                .subject
+                .strip_prefix(&prefix)
+                .and_then(|subject| subject.parse().ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unexpected subject {}", msg.subject),
+                    )
+                })?;
+
+            let delivery = Delivery {
+                stream_seq: info.stream_seq,
+                consumer_seq: info.consumer_seq,
+                delivered: info.delivered as u64,
+                value: jetstream::decode(&msg.data)?,
+            };
+
+            Ok((delivery, subject))
+        });
+
+        match proc_ret {
+            Ok((delivery, subject)) => {
+                c.received.push(Received::Delivery(delivery, subject));
+                self.unvalidated_consumers.insert(id);
+                Outcome::Consumed {
+                    seq: delivery.stream_seq,
+                    value: delivery.value,
+                }
+            }
+            Err(e) => Outcome::Failed {
+                error: e.to_string(),
+            },
+        }
+    }
+
+    fn validate(&mut self) -> Result<(), Violation> {
+        // assert all consumers have witnessed messages in the correct order
+        let unvalidated_consumers = mem::take(&mut self.unvalidated_consumers);
+
+        for id in unvalidated_consumers {
+            let c = &mut self.consumers[id];
+            let stream = c.stream;
+            let filter = c.filter;
+
+            for received in mem::take(&mut c.received) {
+                let (delivery, subject) = match received {
+                    Received::Delivery(delivery, subject) => (delivery, subject),
+                    Received::Deleted => {
+                        c.model = DurableModel::default();
+                        continue;
+                    }
+                };
+                let position = (stream, delivery.stream_seq);
+
+                if let Some(filter) = filter.filter(|filter| *filter != subject) {
+                    return Err(Violation::correctness(
+                        "A consumer received a message that doesn't match its filter subject.",
+                    )
+                    .detail("consumer", &c.name)
+                    .detail("filter subject", self::subject(stream, filter))
+                    .detail("subject", self::subject(stream, subject))
+                    .detail("stream sequence", delivery.stream_seq));
+                }
+
+                self.subject_of.insert(position, subject);
+
+                // a filtered consumer may skip over every message
+                // we don't know to be published to its subject,
+                // and every consumer over those that limits or
+                // purges may have removed.
+                let deleted = &self.durability_model.deleted;
+                let subject_of = &self.subject_of;
+                let limited = self.limited[stream];
+                let purged_below = self.purged_below[stream];
+                c.model.observe(&c.name, &delivery, MAX_DELIVER, |seq| {
+                    deleted.contains(&(stream, seq))
+                        || limited
+                        || seq < purged_below
+                        || (filter.is_some() && subject_of.get(&(stream, seq)) != filter.as_ref())
+                })?;
+
+                self.durability_model.observe(position, delivery.value)?;
+
+                // every consumer whose filter matches has to
+                // receive a message before interest in it ends.
+                let delivered_to = self.delivered_to.entry(position).or_default();
+                delivered_to.insert(id);
+                if self.interest && delivered_to.len() == self.interested[stream][subject] {
+                    self.durability_model.deleted.insert(position);
+                }
+            }
+        }
+
+        self.durability_model.validate_acks()
+    }
+
+    // reads every stream back, keyed by position. acknowledged
+    // writes in front of what's left of a stream that may have
+    // had limits count as removed on purpose.
+    fn read_streams(&mut self, client: &Client) -> io::Result<BTreeMap<Position, u64>> {
+        let mut contents = BTreeMap::new();
+        for stream in 0..self.streams {
+            let name = stream_name(stream);
+            for (seq, value) in jetstream::drain(&client.nc, &name)? {
+                contents.insert((stream, seq), value);
+            }
+
+            // only after reading the stream, so that whatever
+            // expired while we read it counts as removed.
+            if self.limited[stream] {
+                let first_seq = client.nc.stream_info(&name)?.state.first_seq;
+                self.remove_below(stream, first_seq);
+            }
+        }
+        Ok(contents)
+    }
+
+    // lifts all limits, lets the clients read everything
+    // left in their consumers, and then checks that no
+    // acknowledged write was lost.
+    fn finish(&mut self, clients: &[Client]) -> Result<(), Violation> {
+        if self.config_changes {
+            for stream in 0..self.streams {
+                // keeping whatever replicas the stream has now
+                let lifted = (0..3).any(|_| match clients[0].nc.stream_info(stream_name(stream)) {
+                    Ok(info) => {
+                        let replicas = info.config.num_replicas;
+                        let outcome = self.update(&clients[0], stream, None, None, replicas);
+                        outcome == Outcome::Ok
+                    }
+                    Err(e) => {
+                        println!(
+                            "couldn't read the config of {}: {:?}",
+                            stream_name(stream),
+                            e
+                        );
+                        false
+                    }
+                });
+                if !lifted {
+                    println!("couldn't lift the limits of {}", stream_name(stream));
+                }
+            }
+        }
+
+        for _ in 0..DRAIN_ATTEMPTS {
+            for id in 0..self.consumers.len() {
+                while let Outcome::Consumed { .. } = self.consume(id) {}
+            }
+
+            self.validate()?;
+
+            if !self.consumers.iter().any(|c| c.model.has_skipped()) {
+                break;
+            }
+            // a message lost in flight only comes back
+            // once its ack wait expired.
+            std::thread::sleep(ACK_WAIT);
+        }
+
+        for c in &self.consumers {
+            let (stream, filter) = (c.stream, c.filter);
+            c.model.check_drained(&c.name, |seq| {
+                self.durability_model.deleted.contains(&(stream, seq))
+                    || self.limited[stream]
+                    || seq < self.purged_below[stream]
+                    || (filter.is_some() && self.subject_of.get(&(stream, seq)) != filter.as_ref())
+            })?;
+        }
+
+        let streams = super::read_back(self.streams(), || self.read_streams(&clients[0]))?;
+
+        self.durability_model.check_stream(&streams)?;
+        self.durability_model.check_delivered()?;
+
+        println!(
+            "no acknowledged writes were lost out of {}",
+            self.durability_model.acked().len()
+        );
+
+        Ok(())
+    }
+}